@@ -1,60 +1,646 @@
-use chrono::{DateTime, Datelike, Local, NaiveTime, Timelike, Weekday};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Weekday};
+use librespot::core::spotify_id::SpotifyId;
+use std::panic::{self, AssertUnwindSafe};
+use std::{fs, path::Path};
+
+use crate::config::{check_fade, Config};
+use crate::days::DayNames;
 
 #[derive(Debug, Clone)]
 pub struct Alarm {
     pub desc: String,
     pub time: NaiveTime,
     pub days: Vec<Weekday>,
+    /// days that go off at a different time than `time`
+    pub day_times: Vec<(Weekday, NaiveTime)>,
+    /// go off up to this many seconds early or late, by a different amount each day
+    pub jitter_secs: u32,
+    /// move `time` a little each day towards a new time
+    pub ramp: Option<Ramp>,
+    /// group in the config to take unset settings from
+    pub group: Option<String>,
+    pub settings: AlarmSettings,
     pub played: bool,
+    /// working out the alarm's time failed, so it's skipped until it's edited
+    pub broken: bool,
+}
+
+/// Settings an alarm sets itself or takes from its group. Unset means use the group's value,
+/// then the default.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AlarmSettings {
+    /// playlists (or podcast shows/episodes) to pick from, empty uses the default alarm playlist
+    pub playlists: Vec<WeightedPlaylist>,
+    /// continue the playlist from where the last resuming alarm left off instead of shuffling
+    pub resume: Option<bool>,
+    /// end the alarm when its track ends (the default) rather than playing on through the playlist
+    pub stop_on_track_end: Option<bool>,
+    /// beep a few times before the music starts
+    pub pre_chime: Option<bool>,
+    pub action: Option<Action>,
+    pub selection: Option<Selection>,
+    /// these override the config's volume settings of the same name
+    pub volume: Option<u8>,
+    pub fade_in_secs: Option<u32>,
+    pub fade_min_volume: Option<u8>,
+    pub volume_profile: Option<Vec<(u32, u8)>>,
+}
+
+/// What an alarm does when it goes off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// play music from Spotify
+    Play,
+    /// just print a reminder, without any sound
+    Notify,
+}
+
+/// How a track is picked from a shuffled playlist
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    /// every track is as likely
+    Random,
+    /// favour tracks further down the playlist, which are usually the most recently added
+    Recent,
+}
+impl AlarmSettings {
+    /// Fill in anything not set here from `other`
+    pub fn or(&self, other: &AlarmSettings) -> AlarmSettings {
+        AlarmSettings {
+            playlists: if self.playlists.is_empty() {
+                other.playlists.clone()
+            } else {
+                self.playlists.clone()
+            },
+            resume: self.resume.or(other.resume),
+            stop_on_track_end: self.stop_on_track_end.or(other.stop_on_track_end),
+            pre_chime: self.pre_chime.or(other.pre_chime),
+            action: self.action.or(other.action),
+            selection: self.selection.or(other.selection),
+            volume: self.volume.or(other.volume),
+            fade_in_secs: self.fade_in_secs.or(other.fade_in_secs),
+            fade_min_volume: self.fade_min_volume.or(other.fade_min_volume),
+            volume_profile: self
+                .volume_profile
+                .clone()
+                .or_else(|| other.volume_profile.clone()),
+        }
+    }
+
+    /// Parse one `key=value` setting, as used in alarm options and config groups
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "playlists" => self.playlists = parse_playlists(value)?,
+            "resume" => self.resume = Some(parse_bool(key, value)?),
+            "stop_on_track_end" => self.stop_on_track_end = Some(parse_bool(key, value)?),
+            "pre_chime" => self.pre_chime = Some(parse_bool(key, value)?),
+            "action" => {
+                self.action = Some(match value {
+                    "play" => Action::Play,
+                    "notify" => Action::Notify,
+                    _ => return Err(format!("action must be play or notify, got \"{}\"", value)),
+                })
+            }
+            "selection" => {
+                self.selection = Some(match value {
+                    "random" => Selection::Random,
+                    "recent" => Selection::Recent,
+                    _ => {
+                        return Err(format!(
+                            "selection must be random or recent, got \"{}\"",
+                            value
+                        ))
+                    }
+                })
+            }
+            "volume" => self.volume = Some(parse_percent(key, value)?),
+            "fade_in_secs" => self.fade_in_secs = Some(parse_num(key, value)?),
+            "fade_min_volume" => self.fade_min_volume = Some(parse_percent(key, value)?),
+            "volume_profile" => self.volume_profile = Some(parse_volume_profile(value)?),
+            _ => return Err(format!("unknown alarm option \"{}\"", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Move an alarm from its time to `to` in even steps over `days` days, starting on `start`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ramp {
+    pub to: NaiveTime,
+    pub days: u32,
+    pub start: NaiveDate,
+}
+
+impl Ramp {
+    /// Where an alarm at `from` has got to on `date`, to the minute
+    fn time_at(&self, from: NaiveTime, date: NaiveDate) -> NaiveTime {
+        let day = (date - self.start).num_days().clamp(0, self.days as i64);
+        let total = (self.to - from).num_minutes();
+        from + chrono::Duration::minutes(total * day / self.days as i64)
+    }
+}
+
+/// 64-bit FNV-1a. Unlike std's hashers its output is fixed, so an alarm's jitter on a given
+/// day doesn't change with the Rust version it was built with.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedPlaylist {
+    pub uri: String,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceKind {
+    Playlist,
+    Show,
+    Episode,
+}
+
+/// What an alarm source URI points at, or None if it can't be played as an alarm
+pub fn source_kind(uri: &str) -> Option<SourceKind> {
+    let id = uri.rsplit(':').next().unwrap_or_default();
+    if !is_base62_id(id) || SpotifyId::from_uri(uri).is_err() {
+        return None;
+    }
+    match uri.split(':').nth(1) {
+        Some("playlist") => Some(SourceKind::Playlist),
+        Some("show") => Some(SourceKind::Show),
+        Some("episode") => Some(SourceKind::Episode),
+        _ => None,
+    }
+}
+/// Turn a Spotify reference as a user would paste it into a `spotify:` URI. Takes URIs,
+/// open.spotify.com links (with or without a query string) and bare ids, which are taken to
+/// be playlists. Anything else is returned as it is, for `source_kind` to reject.
+pub fn normalize_uri(value: &str) -> String {
+    let value = value.trim();
+    let link = ["https://", "http://", ""]
+        .iter()
+        .find_map(|scheme| value.strip_prefix(&format!("{}open.spotify.com/", scheme)));
+    if let Some(path) = link {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        // links can have a locale first, e.g. /intl-de/playlist/ID
+        let mut parts = path
+            .split('/')
+            .filter(|p| !p.is_empty() && !p.starts_with("intl-"));
+        if let (Some(kind), Some(id), None) = (parts.next(), parts.next(), parts.next()) {
+            return format!("spotify:{}:{}", kind, id);
+        }
+    } else if is_base62_id(value) {
+        return format!("spotify:playlist:{}", value);
+    }
+    value.to_string()
+}
+
+/// Whether `value` is a bare Spotify id. librespot's `from_base62` doesn't check for overflow
+/// and panics on ids that are too long (or too big to fit), so check before asking it.
+fn is_base62_id(value: &str) -> bool {
+    value.len() == 22
+        && value
+            .bytes()
+            .try_fold(0u128, |n, c| {
+                let digit = match c {
+                    b'0'..=b'9' => c - b'0',
+                    b'a'..=b'z' => c - b'a' + 10,
+                    b'A'..=b'Z' => c - b'A' + 36,
+                    _ => return None,
+                };
+                n.checked_mul(62)?.checked_add(digit as u128)
+            })
+            .is_some()
+}
+
 impl PartialEq for Alarm {
     fn eq(&self, other: &Self) -> bool {
-        self.desc == other.desc && self.time == other.time && self.days == other.days
+        self.desc == other.desc
+            && self.time == other.time
+            && self.days == other.days
+            && self.day_times == other.day_times
+            && self.jitter_secs == other.jitter_secs
+            && self.ramp == other.ramp
     }
 }
+/// How an alarm's rules compare to one moment, to see why it is or isn't due
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchResult {
+    /// the date is one of the alarm's days
+    pub day: bool,
+    /// its time that day is in the same minute
+    pub time: bool,
+    /// it already went off
+    pub played: bool,
+}
+impl MatchResult {
+    pub fn is_due(&self) -> bool {
+        self.day && self.time && !self.played
+    }
+}
+
 impl Alarm {
-    /// An alarm should play if it has not already been played and
+    /// An alarm should play if it has not already been played, today is one of its days and
     /// its time's hour and minute are the same as the current time
     pub fn should_play(&self, time: DateTime<Local>) -> bool {
-        if !self.played && self.time.minute() == time.minute() && self.time.hour() == time.hour() {
-            return true;
+        self.matches_at(time).is_due()
+    }
+
+    /// The alarm's settings, with anything it doesn't set taken from its group
+    pub fn settings_in(&self, config: &Config) -> AlarmSettings {
+        match self.group.as_ref().and_then(|g| config.groups.get(g)) {
+            Some(group) => self.settings.or(group),
+            None => self.settings.clone(),
+        }
+    }
+
+    /// Compare the alarm's days, time and played flag with `now`
+    pub fn matches_at(&self, now: DateTime<Local>) -> MatchResult {
+        // a broken alarm's time can't be worked out
+        let time = !self.broken && {
+            let at = self.time_at(now.date_naive());
+            at.minute() == now.minute() && at.hour() == now.hour()
+        };
+        MatchResult {
+            day: self.days.contains(&now.weekday()),
+            time,
+            played: self.played,
+        }
+    }
+
+    /// Identifies the alarm across restarts
+    pub fn key(&self) -> String {
+        format!("{} {}", self.time.format("%H:%M"), self.desc)
+    }
+
+    /// When the alarm goes off on `day`, before any ramp or jitter
+    pub fn time_on(&self, day: Weekday) -> NaiveTime {
+        self.day_times
+            .iter()
+            .find(|(d, _)| *d == day)
+            .map_or(self.time, |(_, t)| *t)
+    }
+
+    /// When the alarm actually goes off on `date`. The jitter is picked from the alarm and the
+    /// date, so it's the same every time it's worked out that day, even after a restart.
+    pub fn time_at(&self, date: NaiveDate) -> NaiveTime {
+        let time = match self.ramp {
+            // days with their own time don't ramp
+            Some(ramp) if !self.day_times.iter().any(|(d, _)| *d == date.weekday()) => {
+                ramp.time_at(self.time, date)
+            }
+            _ => self.time_on(date.weekday()),
+        };
+        if self.jitter_secs == 0 {
+            return time;
         }
-        false
+        let hash = fnv1a(format!("{} {}", self.key(), date.format("%Y-%m-%d")).as_bytes());
+        let jitter = self.jitter_secs as u64;
+        let offset = (hash % (2 * jitter + 1)) as i64 - jitter as i64;
+        time + chrono::Duration::seconds(offset)
+    }
+
+    /// Whether playing the alarm at `time`, e.g. with `fire`, stands in for today's: it's one
+    /// of the alarm's days and its time has come, or is within `grace_secs`
+    pub fn stands_in_for_today(&self, time: DateTime<Local>, grace_secs: u32) -> bool {
+        let date = time.date_naive();
+        let at = date.and_time(self.time_at(date));
+        self.days.contains(&date.weekday())
+            && time.naive_local() + chrono::Duration::seconds(grace_secs as i64) >= at
+    }
+
+    /// Why the alarm will or won't go off, as of `time`. `played` is whether it already went
+    /// off today.
+    pub fn explain(&self, time: DateTime<Local>, played: bool, config: &Config) -> Vec<String> {
+        let today = time.date_naive();
+        let days = self
+            .days
+            .iter()
+            .map(|d| config.day_names.format(*d))
+            .collect::<Vec<String>>();
+        let mut lines = vec![format!("Goes off on {}", days.join(","))];
+        if !self.days.contains(&today.weekday()) {
+            lines.push(format!("Not today, it's {}", today.weekday()));
+        } else if let Some(range) = config.disabled_on(today) {
+            lines.push(format!("Not today, alarms are disabled until {}", range.to));
+        } else if played {
+            lines.push("Already went off today".to_string());
+        } else {
+            let at = self.time_at(today);
+            if self.matches_at(time).is_due() {
+                lines.push(format!("Due now, at {}", at.format("%H:%M:%S")));
+            } else if due_later(at, time) {
+                lines.push(format!("Goes off today at {}", at.format("%H:%M:%S")));
+            } else {
+                lines.push(format!("Was due today at {}", at.format("%H:%M:%S")));
+            }
+        }
+
+        match self.occurrences(time, played, config).next() {
+            Some(next) => lines.push(format!(
+                "Next goes off {}",
+                next.format("%a %Y-%m-%d at %H:%M:%S")
+            )),
+            None => lines.push("Won't go off in the next year".to_string()),
+        }
+        if self.settings_in(config).action == Some(Action::Notify) {
+            lines.push("Prints a reminder instead of playing anything".to_string());
+        }
+        lines
+    }
+
+    /// The times the alarm will go off from `time` on, for the next year. `played` is whether
+    /// it already went off today.
+    pub fn occurrences<'a>(
+        &'a self,
+        time: DateTime<Local>,
+        played: bool,
+        config: &'a Config,
+    ) -> impl Iterator<Item = NaiveDateTime> + 'a {
+        let today = time.date_naive();
+        today.iter_days().take(367).filter_map(move |date| {
+            let at = self.time_at(date);
+            let ok = self.days.contains(&date.weekday())
+                && config.disabled_on(date).is_none()
+                && (date != today || (!played && due_later(at, time)));
+            ok.then_some(date.and_time(at))
+        })
     }
 }
 
-pub fn get_alarms(f: &str) -> Result<Vec<Alarm>, String> {
-    let alarms: Vec<Alarm> = f
-        .lines()
-        .filter(|e| !e.starts_with("#")) // skip commented out alarms
-        .filter_map(|line| {
-            let spl = line.split(' ').collect::<Vec<&str>>();
+/// Whether `at` is still to come at `time`. The minute it's due in is still in time, like
+/// `should_play`.
+fn due_later(at: NaiveTime, time: DateTime<Local>) -> bool {
+    (at.hour(), at.minute()) >= (time.hour(), time.minute())
+}
 
-            let times = spl[0]
-                .split(':')
-                .filter_map(|e| e.parse::<u32>().ok())
-                .collect::<Vec<u32>>();
-            if times.len() < 2 {
-                return None;
+/// Load the alarms from a file, or from every `*.txt` file in a directory (in name order)
+pub fn load_alarms(path: &Path, day_names: &DayNames) -> Result<Vec<Alarm>, String> {
+    if !path.is_dir() {
+        let f = fs::read_to_string(path)
+            .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        return get_alarms(&f, day_names).map_err(|e| format!("{} {}", path.display(), e));
+    }
+
+    let mut files = fs::read_dir(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "txt"))
+        .collect::<Vec<_>>();
+    files.sort();
+
+    let mut alarms = vec![];
+    for file in files {
+        alarms.extend(load_alarms(&file, day_names)?);
+    }
+    Ok(alarms)
+}
+
+pub fn get_alarms(f: &str, day_names: &DayNames) -> Result<Vec<Alarm>, String> {
+    let mut alarms = vec![];
+    for (i, line) in f.lines().enumerate() {
+        if line.starts_with('#') {
+            continue; // skip commented out alarms
+        }
+        if let Some(alarm) =
+            parse_alarm(line, day_names).map_err(|e| format!("line {}: {}", i + 1, e))?
+        {
+            alarms.push(alarm);
+        }
+    }
+    Ok(alarms)
+}
+
+/// Parse a line like `6:00 M,T,W [option=value ...] description`.
+/// Blank lines are skipped, anything else that isn't a valid alarm is an error.
+fn parse_alarm(line: &str, day_names: &DayNames) -> Result<Option<Alarm>, String> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let spl = line.split(' ').collect::<Vec<&str>>();
+
+    let time = parse_time(spl[0]).ok_or_else(|| format!("invalid time \"{}\"", spl[0]))?;
+    let days = spl
+        .get(1)
+        .ok_or_else(|| format!("no days after \"{}\"", spl[0]))?
+        .split(',')
+        .map(|d| {
+            day_names
+                .parse(d)
+                .ok_or_else(|| format!("unknown day \"{}\"", d))
+        })
+        .collect::<Result<Vec<Weekday>, String>>()?;
+
+    // options come between the days and the description
+    let mut group = None;
+    let mut day_times = vec![];
+    let mut jitter_secs = 0;
+    let (mut ramp_to, mut ramp_days, mut ramp_start) = (None, None, None);
+    let mut settings = AlarmSettings::default();
+    let mut i = 2;
+    while let Some((key, value)) = spl.get(i).and_then(|e| e.split_once('=')) {
+        match key {
+            "group" => group = Some(value.to_string()),
+            "at" => day_times = parse_day_times(value, &days, day_names)?,
+            "jitter" => {
+                jitter_secs = value
+                    .parse::<u32>()
+                    .map_err(|_| format!("jitter must be a whole number, got \"{}\"", value))?
+            }
+            "ramp_to" => {
+                ramp_to =
+                    Some(parse_time(value).ok_or_else(|| format!("invalid time \"{}\"", value))?)
+            }
+            "ramp_days" => match value.parse::<u32>() {
+                Ok(d) if d > 0 => ramp_days = Some(d),
+                _ => return Err(format!("ramp_days must be at least 1, got \"{}\"", value)),
+            },
+            "ramp_start" => {
+                ramp_start = Some(
+                    value
+                        .parse::<NaiveDate>()
+                        .map_err(|_| format!("invalid date \"{}\", expected YYYY-MM-DD", value))?,
+                )
             }
-            let time = chrono::NaiveTime::from_hms_opt(times[0], times[1], 0).unwrap();
+            _ => settings.set(key, value)?,
+        }
+        i += 1;
+    }
+    let ramp = match (ramp_to, ramp_days, ramp_start) {
+        (Some(to), Some(days), Some(start)) => Some(Ramp { to, days, start }),
+        (None, None, None) => None,
+        _ => return Err("ramp_to, ramp_days and ramp_start must be set together".to_string()),
+    };
 
-            let days = spl[1]
-                .split(',')
-                .filter_map(|e| to_weekday(e))
-                .collect::<Vec<Weekday>>();
+    let desc = spl[i..].join(" "); // everything else is the description
 
-            let desc = spl[2..].join(" "); // everything else is the description
-            Some(Alarm {
-                desc,
-                time,
-                days,
-                played: false,
-            })
+    // jitter that crossed midnight would move the alarm to another day
+    let jitter = chrono::Duration::seconds(jitter_secs as i64);
+    let ramp_to = ramp.map(|r| r.to);
+    for t in day_times
+        .iter()
+        .map(|(_, t)| *t)
+        .chain([time])
+        .chain(ramp_to)
+    {
+        if t.overflowing_sub_signed(jitter).1 != 0 || t.overflowing_add_signed(jitter).1 != 0 {
+            return Err(format!("jitter of {}s would cross midnight", jitter_secs));
+        }
+    }
+    Ok(Some(Alarm {
+        desc,
+        time,
+        days,
+        day_times,
+        jitter_secs,
+        ramp,
+        group,
+        settings,
+        played: false,
+        broken: false,
+    }))
+}
+
+/// Parse `day@time,day@time`, e.g. `S@8:00,Su@8:30`, for days that differ from the alarm's time
+fn parse_day_times(
+    value: &str,
+    days: &[Weekday],
+    day_names: &DayNames,
+) -> Result<Vec<(Weekday, NaiveTime)>, String> {
+    value
+        .split(',')
+        .map(|e| {
+            let (day, time) = e
+                .split_once('@')
+                .ok_or_else(|| format!("expected day@time, got \"{}\"", e))?;
+            let day = day_names
+                .parse(day)
+                .ok_or_else(|| format!("invalid day \"{}\"", day))?;
+            if !days.contains(&day) {
+                return Err(format!(
+                    "{} isn't one of the alarm's days",
+                    day_names.format(day)
+                ));
+            }
+            let time = parse_time(time).ok_or_else(|| format!("invalid time \"{}\"", time))?;
+            Ok((day, time))
         })
-        .collect();
+        .collect()
+}
+
+/// Parse `H:MM`
+fn parse_time(value: &str) -> Option<NaiveTime> {
+    let (h, m) = value.split_once(':')?;
+    NaiveTime::from_hms_opt(h.parse().ok()?, m.parse().ok()?, 0)
+}
 
-    return Ok(alarms);
+pub fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("{} must be true or false, got \"{}\"", key, value)),
+    }
+}
+
+/// Parse `SECS:PERCENT,...`, with the seconds in increasing order
+pub fn parse_volume_profile(value: &str) -> Result<Vec<(u32, u8)>, String> {
+    let mut points: Vec<(u32, u8)> = vec![];
+    for point in value.split(',') {
+        let Some((secs, volume)) = point.trim().split_once(':') else {
+            return Err(format!(
+                "expected SECS:VOLUME in volume_profile, got \"{}\"",
+                point
+            ));
+        };
+        let secs = parse_num("volume_profile seconds", secs.trim())?;
+        let volume = parse_percent("volume_profile volume", volume.trim())?;
+        if points.last().is_some_and(|(last, _)| *last >= secs) {
+            return Err(format!(
+                "volume_profile points must be in order of time, {}s is out of order",
+                secs
+            ));
+        }
+        points.push((secs, volume));
+    }
+    Ok(points)
+}
+
+pub fn parse_percent(key: &str, value: &str) -> Result<u8, String> {
+    match parse_num(key, value)? {
+        v @ 0..=100 => Ok(v as u8),
+        _ => Err(format!("{} must be 0-100, got {}", key.trim(), value)),
+    }
+}
+
+pub fn parse_num(key: &str, value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .map_err(|_| format!("{} must be a whole number, got \"{}\"", key.trim(), value))
+}
+
+/// Parse `uri[*weight],uri[*weight]`. Weights default to 1.
+fn parse_playlists(value: &str) -> Result<Vec<WeightedPlaylist>, String> {
+    let playlists = value
+        .split(',')
+        .map(|e| {
+            let (uri, weight) = match e.split_once('*') {
+                Some((uri, weight)) => (
+                    uri,
+                    weight
+                        .parse::<f64>()
+                        .map_err(|_| format!("invalid playlist weight \"{}\"", weight))?,
+                ),
+                None => (e, 1.0),
+            };
+            let uri = normalize_uri(uri);
+            if source_kind(&uri).is_none() {
+                return Err(format!(
+                    "invalid playlist uri \"{}\", expected a playlist, show or episode",
+                    uri
+                ));
+            }
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(format!(
+                    "playlist weight must be non-negative, got {}",
+                    weight
+                ));
+            }
+            Ok(WeightedPlaylist { uri, weight })
+        })
+        .collect::<Result<Vec<WeightedPlaylist>, String>>()?;
+    if !playlists.iter().any(|p| p.weight > 0.0) {
+        return Err("at least one playlist weight must be positive".to_string());
+    }
+    Ok(playlists)
+}
+
+/// Check the alarms against the config, e.g. that every group they use exists
+pub fn check_alarms(alarms: Vec<Alarm>, config: &Config) -> Result<Vec<Alarm>, String> {
+    if alarms.len() > config.max_alarms as usize {
+        return Err(format!(
+            "{} alarms is more than max_alarms ({})",
+            alarms.len(),
+            config.max_alarms
+        ));
+    }
+    for a in alarms.iter() {
+        if let Some(group) = &a.group {
+            if !config.groups.contains_key(group) {
+                return Err(format!("\"{}\" uses unknown group \"{}\"", a.desc, group));
+            }
+        }
+        let settings = a.settings_in(config);
+        check_fade(
+            settings.volume.unwrap_or(config.volume),
+            settings.fade_in_secs.unwrap_or(config.fade_in_secs),
+            settings.fade_min_volume.unwrap_or(config.fade_min_volume),
+        )
+        .map_err(|e| format!("\"{}\": {}", a.desc, e))?;
+    }
+    Ok(alarms)
 }
 
 /// Get the alarms that still need to be run for today.
@@ -63,45 +649,45 @@ pub fn get_valid_alarms(
     alarms: Vec<Alarm>,
     time: DateTime<Local>,
 ) -> Vec<Alarm> {
-    let mut alarms: Vec<Alarm> = new_alarms
+    let date = time.date_naive();
+    let mut alarms: Vec<(Alarm, NaiveTime)> = new_alarms
         .into_iter()
         .filter_map(|mut a| {
             // is the alarm valid for today?
             if !a.days.contains(&time.weekday()) {
                 return None;
             }
+            if let Some(old) = alarms.iter().rev().find(|old| a == **old) {
+                a.played = old.played;
+                a.broken = old.broken;
+            }
+            if a.broken {
+                let at = a.time;
+                return Some((a, at));
+            }
+            // one alarm panicking shouldn't take the others down, so skip it until it's edited
+            let Ok(at) = panic::catch_unwind(AssertUnwindSafe(|| a.time_at(date))) else {
+                eprintln!("Skipping {:?}, unable to work out when it goes off", a.desc);
+                a.broken = true;
+                a.played = true;
+                let at = a.time;
+                return Some((a, at));
+            };
             // is the alarm in the past?
-            if a.time.hour() <= time.hour() && a.time.minute() < time.minute() {
+            if at.hour() <= time.hour() && at.minute() < time.minute() {
                 return None;
             }
-            for alrm in alarms.iter() {
-                if a == *alrm {
-                    a.played = alrm.played;
-                }
-            }
-            Some(a)
+            Some((a, at))
         })
         .collect();
-    alarms.sort_by(|a, b| a.time.cmp(&b.time));
-    alarms
-}
-
-fn to_weekday(d: &str) -> Option<Weekday> {
-    match d {
-        "M" => Some(Weekday::Mon),
-        "T" => Some(Weekday::Tue),
-        "W" => Some(Weekday::Wed),
-        "Th" => Some(Weekday::Thu),
-        "F" => Some(Weekday::Fri),
-        "S" => Some(Weekday::Sat),
-        "Su" => Some(Weekday::Sun),
-        _ => None,
-    }
+    alarms.sort_by_key(|(_, at)| *at);
+    alarms.into_iter().map(|(a, _)| a).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::TimeZone;
 
     #[test]
     fn test_get_alarms() {
@@ -110,34 +696,447 @@ mod tests {
 6:17 M,T,F,S,Su this is the second alarm
 6:17 M,T,F,S,Su"
             .to_string();
-        let alarms = get_alarms(&alarms).unwrap();
+        let alarms = get_alarms(&alarms, &DayNames::default()).unwrap();
         assert_eq!(alarms.len(), 3);
     }
     #[test]
+    fn test_get_alarms_playlists() {
+        let alarms = "7:00 M,F playlists=spotify:playlist:2aBMj4vGrpxavecIWQtcc4*70,spotify:playlist:37i9dQZF1DX0UrRvztWcAU*30 mixed up";
+        let alarms = get_alarms(alarms, &DayNames::default()).unwrap();
+        assert_eq!(alarms[0].desc, "mixed up");
+        assert_eq!(
+            alarms[0].settings.playlists[1],
+            WeightedPlaylist {
+                uri: "spotify:playlist:37i9dQZF1DX0UrRvztWcAU".to_string(),
+                weight: 30.0
+            }
+        );
+
+        let alarms = get_alarms(
+            "7:00 M playlists=spotify:playlist:2aBMj4vGrpxavecIWQtcc4",
+            &DayNames::default(),
+        )
+        .unwrap();
+        assert_eq!(alarms[0].settings.playlists[0].weight, 1.0);
+
+        assert!(get_alarms(
+            "7:00 M playlists=spotify:playlist:2aBMj4vGrpxavecIWQtcc4*0",
+            &DayNames::default()
+        )
+        .is_err());
+        assert!(get_alarms(
+            "7:00 M playlists=spotify:playlist:2aBMj4vGrpxavecIWQtcc4*-1",
+            &DayNames::default()
+        )
+        .is_err());
+        assert!(get_alarms("7:00 M playlists=not-a-uri", &DayNames::default()).is_err());
+        assert!(get_alarms(
+            "7:00 M playlists=spotify:track:5PbMSJZcNA3p2LZv7C56cm",
+            &DayNames::default()
+        )
+        .is_err());
+
+        let alarms = get_alarms(
+            "7:00 M playlists=spotify:show:2mTUnDkuKUkhiueKcVWoP0 news",
+            &DayNames::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            source_kind(&alarms[0].settings.playlists[0].uri),
+            Some(SourceKind::Show)
+        );
+        assert_eq!(
+            source_kind("spotify:episode:4GNcXTGWmnZ3ySrqvol3o4"),
+            Some(SourceKind::Episode)
+        );
+        assert!(get_alarms("7:00 M volume=101", &DayNames::default()).is_err());
+        assert!(get_alarms("7:00 M loud=11", &DayNames::default()).is_err());
+        assert_eq!(
+            get_alarms("7:00 M ok\n25:00 M late", &DayNames::default()).unwrap_err(),
+            "line 2: invalid time \"25:00\""
+        );
+
+        let alarms = get_alarms("7:00 M resume=true audiobook", &DayNames::default()).unwrap();
+        assert_eq!(alarms[0].settings.resume, Some(true));
+        assert_eq!(alarms[0].desc, "audiobook");
+        assert!(get_alarms("7:00 M resume=yes", &DayNames::default()).is_err());
+        let alarms =
+            get_alarms("7:00 M action=notify take medication", &DayNames::default()).unwrap();
+        assert_eq!(alarms[0].settings.action, Some(Action::Notify));
+        assert!(get_alarms("7:00 M action=shout", &DayNames::default()).is_err());
+        let alarms =
+            get_alarms("7:00 M stop_on_track_end=false radio", &DayNames::default()).unwrap();
+        assert_eq!(alarms[0].settings.stop_on_track_end, Some(false));
+
+        let alarms = get_alarms(
+            "6:30 M,T group=weekday resume=false work",
+            &DayNames::default(),
+        )
+        .unwrap();
+        assert_eq!(alarms[0].group, Some("weekday".to_string()));
+        assert_eq!(alarms[0].desc, "work");
+    }
+    #[test]
+    fn day_time_overrides() {
+        let alarms = get_alarms(
+            "6:30 M,T,W,Th,F,S,Su at=S@8:00,Su@8:30 wake",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let alarm = &alarms[0];
+        assert_eq!(alarm.desc, "wake");
+        assert_eq!(
+            alarm.time_on(Weekday::Mon),
+            NaiveTime::from_hms_opt(6, 30, 0).unwrap()
+        );
+        assert_eq!(
+            alarm.time_on(Weekday::Sun),
+            NaiveTime::from_hms_opt(8, 30, 0).unwrap()
+        );
+
+        // 2024-01-06 is a Saturday
+        let sat = Local.with_ymd_and_hms(2024, 1, 6, 6, 30, 0).unwrap();
+        assert!(!alarm.should_play(sat));
+        assert!(alarm.should_play(Local.with_ymd_and_hms(2024, 1, 6, 8, 0, 0).unwrap()));
+        assert!(alarm.should_play(Local.with_ymd_and_hms(2024, 1, 8, 6, 30, 0).unwrap()));
+        assert_eq!(
+            alarm.matches_at(sat),
+            MatchResult {
+                day: true,
+                time: false,
+                played: false
+            }
+        );
+        let tue = Local.with_ymd_and_hms(2024, 1, 2, 6, 30, 0).unwrap();
+        let mut weekends = get_alarms("6:30 S,Su weekend", &DayNames::default()).unwrap();
+        assert!(!weekends[0].matches_at(tue).day && weekends[0].matches_at(tue).time);
+        assert!(weekends[0].matches_at(sat).is_due());
+        weekends[0].played = true;
+        assert!(!weekends[0].matches_at(sat).is_due());
+        // still to come on Saturday even though 6:30 has passed
+        assert_eq!(
+            get_valid_alarms(alarms.clone(), vec![], sat + chrono::Duration::hours(1)).len(),
+            1
+        );
+
+        assert!(get_alarms("6:30 M,S at=S@25:00", &DayNames::default()).is_err());
+        assert!(get_alarms("6:30 M,S at=S8:00", &DayNames::default()).is_err());
+        assert!(get_alarms("6:30 M,S at=X@8:00", &DayNames::default()).is_err());
+        assert!(get_alarms("6:30 M at=S@8:00", &DayNames::default()).is_err());
+    }
+    #[test]
+    fn rejects_malformed_lines() {
+        let day_names = DayNames::default();
+        assert_eq!(
+            get_alarms("6:00 M wake\n\n# 6:00 T off\n", &day_names)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            get_alarms("6:00 M wake\n6:00 Mo,Tu wake", &day_names).unwrap_err(),
+            "line 2: unknown day \"Mo\""
+        );
+        assert_eq!(
+            get_alarms("6.30 M wake", &day_names).unwrap_err(),
+            "line 1: invalid time \"6.30\""
+        );
+        assert_eq!(
+            get_alarms("6:30", &day_names).unwrap_err(),
+            "line 1: no days after \"6:30\""
+        );
+        assert!(get_alarms("6:00 M,,T wake", &day_names).is_err());
+    }
+    #[test]
+    fn jitter_is_fixed_for_the_day() {
+        let alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su jitter=600 natural",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let alarm = &alarms[0];
+        let date = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let base = NaiveTime::from_hms_opt(7, 0, 0).unwrap();
+        let times = date
+            .iter_days()
+            .take(30)
+            .map(|d| alarm.time_at(d))
+            .collect::<Vec<NaiveTime>>();
+        assert!(times.iter().all(|t| (*t - base).num_seconds().abs() <= 600));
+        assert!(times.iter().any(|t| *t != times[0]));
+        assert_eq!(alarm.time_at(date), times[0]);
+        // the same on every build
+        assert_eq!(times[0], NaiveTime::from_hms_opt(6, 59, 46).unwrap());
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        assert!(get_alarms("0:05 M jitter=600", &DayNames::default()).is_err());
+        assert!(get_alarms("23:55 M jitter=600", &DayNames::default()).is_err());
+        assert!(get_alarms("12:00 M at=M@23:58 jitter=600", &DayNames::default()).is_err());
+        assert!(get_alarms("7:00 M jitter=-5", &DayNames::default()).is_err());
+    }
+    #[test]
+    fn ramp_moves_the_time_each_day() {
+        let alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su ramp_to=6:00 ramp_days=4 ramp_start=2024-01-01 at=Su@9:00 early",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let alarm = &alarms[0];
+        let date = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert_eq!(alarm.time_at(date(1)), time(7, 0));
+        assert_eq!(alarm.time_at(date(2)), time(6, 45));
+        assert_eq!(alarm.time_at(date(4)), time(6, 15));
+        assert_eq!(alarm.time_at(date(5)), time(6, 0));
+        // 2024-01-07 is a Sunday
+        assert_eq!(alarm.time_at(date(7)), time(9, 0));
+        assert_eq!(alarm.time_at(date(20)), time(6, 0));
+        // before the ramp starts
+        let before = NaiveDate::from_ymd_opt(2023, 12, 25).unwrap();
+        assert_eq!(alarm.time_at(before), time(7, 0));
+
+        assert!(get_alarms("7:00 M ramp_to=6:00 ramp_days=4", &DayNames::default()).is_err());
+        assert!(get_alarms(
+            "7:00 M ramp_to=6:00 ramp_days=0 ramp_start=2024-01-01",
+            &DayNames::default()
+        )
+        .is_err());
+        assert!(get_alarms(
+            "7:00 M ramp_to=6:61 ramp_days=4 ramp_start=2024-01-01",
+            &DayNames::default()
+        )
+        .is_err());
+    }
+    #[test]
+    fn manual_fires_only_count_once_the_alarm_is_due() {
+        let alarms = get_alarms("7:00 M,W wake", &DayNames::default()).unwrap();
+        let at = |h, m| Local.with_ymd_and_hms(2024, 1, 1, h, m, 0).unwrap();
+        // testing it the evening before leaves the morning's alarm alone
+        assert!(!alarms[0].stands_in_for_today(at(6, 0), 90));
+        assert!(alarms[0].stands_in_for_today(at(6, 59), 90));
+        assert!(alarms[0].stands_in_for_today(at(7, 0), 0));
+        assert!(alarms[0].stands_in_for_today(at(22, 0), 90));
+        // not one of its days
+        let tuesday = Local.with_ymd_and_hms(2024, 1, 2, 8, 0, 0).unwrap();
+        assert!(!alarms[0].stands_in_for_today(tuesday, 90));
+    }
+    #[test]
+    fn explains_when_it_goes_off() {
+        let config = crate::config::get_config("disable=2024-01-03..2024-01-07").unwrap();
+        let alarms = get_alarms("7:00 M,W why", &DayNames::default()).unwrap();
+        // a Monday
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 6, 0, 0).unwrap();
+        assert_eq!(
+            alarms[0].explain(time, false, &config),
+            vec![
+                "Goes off on M,W",
+                "Goes off today at 07:00:00",
+                "Next goes off Mon 2024-01-01 at 07:00:00"
+            ]
+        );
+        // Wednesday is disabled, so it's the Monday after
+        assert_eq!(
+            alarms[0].explain(time, true, &config)[1..],
+            [
+                "Already went off today",
+                "Next goes off Mon 2024-01-08 at 07:00:00"
+            ]
+        );
+        let time = Local.with_ymd_and_hms(2024, 1, 2, 7, 0, 0).unwrap();
+        assert_eq!(
+            alarms[0].explain(time, false, &config)[1],
+            "Not today, it's Tue"
+        );
+        let time = Local.with_ymd_and_hms(2024, 1, 3, 7, 0, 0).unwrap();
+        assert_eq!(
+            alarms[0].explain(time, false, &config)[1],
+            "Not today, alarms are disabled until 2024-01-07"
+        );
+
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap();
+        let next = alarms[0]
+            .occurrences(time, false, &config)
+            .take(3)
+            .map(|t| t.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            next,
+            [
+                "2024-01-08 07:00:00",
+                "2024-01-10 07:00:00",
+                "2024-01-15 07:00:00"
+            ]
+        );
+        let config = crate::config::get_config("disable=2024-01-01..2025-12-31").unwrap();
+        assert_eq!(alarms[0].occurrences(time, false, &config).count(), 0);
+
+        // an action set by the alarm's group counts too
+        let config = crate::config::get_config("group.pills.action=notify").unwrap();
+        let alarms = get_alarms("7:00 M group=pills medicine", &DayNames::default()).unwrap();
+        assert_eq!(
+            alarms[0].explain(time, false, &config).last().unwrap(),
+            "Prints a reminder instead of playing anything"
+        );
+    }
+    #[test]
+    fn skips_alarms_that_panic() {
+        let mut alarms = get_alarms(
+            "6:00 M,T,W,Th,F,S,Su fine\n7:00 M,T,W,Th,F,S,Su bad",
+            &DayNames::default(),
+        )
+        .unwrap();
+        // the parser won't allow this, a 0 day ramp divides by zero
+        alarms[1].ramp = Some(Ramp {
+            to: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            days: 0,
+            start: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+        });
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 5, 0, 0).unwrap();
+        let valid = get_valid_alarms(alarms.clone(), vec![], time);
+        assert_eq!(valid.len(), 2);
+        assert!(!valid[0].broken && !valid[0].played);
+        assert!(valid[1].broken && valid[1].played);
+        assert!(!valid[1].should_play(Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap()));
+
+        // it stays skipped without being worked out again
+        let valid = get_valid_alarms(alarms, valid, time);
+        assert!(valid[1].broken);
+    }
+    #[test]
+    fn normalizes_pasted_uris() {
+        let uri = "spotify:playlist:37i9dQZF1DX0UrRvztWcAU";
+        for pasted in [
+            uri,
+            "37i9dQZF1DX0UrRvztWcAU",
+            "https://open.spotify.com/playlist/37i9dQZF1DX0UrRvztWcAU",
+            "https://open.spotify.com/playlist/37i9dQZF1DX0UrRvztWcAU?si=d83209b036a64047",
+            "open.spotify.com/intl-de/playlist/37i9dQZF1DX0UrRvztWcAU/",
+            " http://open.spotify.com/playlist/37i9dQZF1DX0UrRvztWcAU#top ",
+        ] {
+            assert_eq!(normalize_uri(pasted), uri, "{}", pasted);
+        }
+        assert_eq!(
+            normalize_uri("https://open.spotify.com/show/2mTUnDkuKUkhiueKcVWoP0?si=1"),
+            "spotify:show:2mTUnDkuKUkhiueKcVWoP0"
+        );
+        assert_eq!(normalize_uri("not-a-uri"), "not-a-uri");
+        let long = "37i9dQZF1DX0UrRvztWcAU37i9dQZF1DX0UrRvztWcAU";
+        assert_eq!(normalize_uri(long), long);
+        assert!(get_alarms(
+            &format!("7:00 M playlists={} long", long),
+            &DayNames::default()
+        )
+        .is_err());
+        // 22 characters, but too big for an id
+        let big = "zzzzzzzzzzzzzzzzzzzzzz";
+        assert_eq!(normalize_uri(big), big);
+        assert!(source_kind(&format!("spotify:playlist:{}", big)).is_none());
+        assert_eq!(
+            normalize_uri("https://example.com/playlist/37i9dQZF1DX0UrRvztWcAU"),
+            "https://example.com/playlist/37i9dQZF1DX0UrRvztWcAU"
+        );
+
+        let alarms = get_alarms(
+            "7:00 M playlists=https://open.spotify.com/playlist/37i9dQZF1DX0UrRvztWcAU?si=x*3",
+            &DayNames::default(),
+        )
+        .unwrap();
+        assert_eq!(alarms[0].settings.playlists[0].uri, uri);
+        assert_eq!(alarms[0].settings.playlists[0].weight, 3.0);
+    }
+    #[test]
+    fn settings_override_group() {
+        let mut group = AlarmSettings::default();
+        group
+            .set("playlists", "spotify:playlist:2aBMj4vGrpxavecIWQtcc4")
+            .unwrap();
+        group.set("resume", "true").unwrap();
+        let mut alarm = AlarmSettings::default();
+        alarm.set("resume", "false").unwrap();
+
+        let settings = alarm.or(&group);
+        assert_eq!(settings.playlists, group.playlists);
+        assert_eq!(settings.resume, Some(false));
+        assert_eq!(AlarmSettings::default().or(&group), group);
+    }
+    #[test]
+    fn checks_each_alarms_fade() {
+        let config = crate::config::get_config("fade_in_secs=30\ngroup.quiet.volume=20").unwrap();
+        let check = |alarms| check_alarms(get_alarms(alarms, &config.day_names).unwrap(), &config);
+        assert!(check("7:00 M fade_min_volume=50 a").is_ok());
+        assert!(check("7:00 M group=quiet fade_min_volume=50 a").is_err());
+        assert!(check("7:00 M group=quiet fade_min_volume=50 fade_in_secs=0 a").is_ok());
+    }
+    #[test]
+    fn test_load_alarms_dir() {
+        let dir = std::env::temp_dir().join(format!("spotify-alarm-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("work.txt"), "6:00 M,T,W,Th,F work").unwrap();
+        fs::write(dir.join("home.txt"), "8:00 S,Su home\n9:00 S,Su brunch").unwrap();
+        fs::write(dir.join("notes.md"), "not an alarm").unwrap();
+
+        let alarms = load_alarms(&dir, &DayNames::default()).unwrap();
+        let descs = alarms
+            .iter()
+            .map(|a| a.desc.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(descs, vec!["home", "brunch", "work"]);
+        assert_eq!(
+            load_alarms(&dir.join("work.txt"), &DayNames::default())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        fs::write(dir.join("bad.txt"), "6:00 M nope=1").unwrap();
+        assert!(load_alarms(&dir, &DayNames::default())
+            .unwrap_err()
+            .contains("bad.txt line 1"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
     fn alarm_equals() {
         let alarm1 = Alarm {
             desc: "Wake up".to_string(),
             time: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
             days: vec![Weekday::Mon, Weekday::Wed],
+            day_times: vec![],
+            jitter_secs: 0,
+            ramp: None,
+            group: None,
+            settings: AlarmSettings::default(),
             played: false,
+            broken: false,
         };
 
         let alarm2 = Alarm {
             desc: "Wake up".to_string(),
             time: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
             days: vec![Weekday::Mon, Weekday::Wed],
+            day_times: vec![],
+            jitter_secs: 0,
+            ramp: None,
+            group: None,
+            settings: AlarmSettings::default(),
             played: false,
+            broken: false,
         };
 
         let alarm3 = Alarm {
             desc: "Workout".to_string(),
             time: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
             days: vec![Weekday::Tue, Weekday::Thu],
+            day_times: vec![],
+            jitter_secs: 0,
+            ramp: None,
+            group: None,
+            settings: AlarmSettings::default(),
             played: false,
+            broken: false,
         };
 
         assert_eq!(alarm1, alarm2);
         assert_ne!(alarm1, alarm3);
-        assert!(vec![alarm1.clone(), alarm2].contains(&alarm1));
+        assert!([alarm1.clone(), alarm2].contains(&alarm1));
     }
 }
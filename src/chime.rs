@@ -0,0 +1,184 @@
+use librespot::playback::config::AudioFormat;
+use librespot::playback::convert::Converter;
+use librespot::playback::decoder::AudioPacket;
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use std::f64::consts::PI;
+use std::fs;
+
+use crate::config::Config;
+use crate::spotify::{find_backend, PlayError};
+
+const CHIME_HZ: f64 = 880.0;
+const BEEPS: usize = 3;
+const BEEP_SECS: f64 = 0.25;
+const GAP_SECS: f64 = 0.2;
+/// how loud the chime is at full volume, the music is 1.0
+const LEVEL: f64 = 0.3;
+
+/// Play the chime file, or a few short beeps, through the audio backend at `volume` percent,
+/// returning once it's finished
+pub async fn chime(config: &Config, volume: u8) -> Result<(), PlayError> {
+    let backend = find_backend(config.audio_backend.clone())?;
+    let samples = match &config.chime_file {
+        // the chime doesn't need Spotify, so it should always play something
+        Some(path) => match fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| read_wav(&f))
+        {
+            Ok(samples) => scale(samples, volume),
+            Err(e) => {
+                eprintln!("Unable to read {}, beeping instead: {}", path.display(), e);
+                chime_samples(volume)
+            }
+        },
+        None => chime_samples(volume),
+    };
+    // a sink blocks while it plays, and panics if its device can't be opened
+    tokio::task::spawn_blocking(move || {
+        let mut sink = backend(None, AudioFormat::default());
+        let mut converter = Converter::new(None);
+        sink.start()?;
+        // some sinks only buffer what they're given and stop when they're dropped, so write
+        // in small chunks and finish with a second of silence to let the beeps drain
+        let silence = vec![0.0; SAMPLE_RATE as usize * NUM_CHANNELS as usize];
+        for chunk in samples.chunks(2048).chain(silence.chunks(2048)) {
+            sink.write(AudioPacket::Samples(chunk.to_vec()), &mut converter)?;
+        }
+        sink.stop()
+    })
+    .await
+    .map_err(|_| PlayError::Transient("unable to open the audio device".to_string()))?
+    .map_err(|e| PlayError::Transient(format!("unable to play the chime: {}", e)))
+}
+
+fn scale(samples: Vec<f64>, volume: u8) -> Vec<f64> {
+    samples
+        .into_iter()
+        .map(|s| s * volume as f64 / 100.0)
+        .collect()
+}
+
+/// Decode a 16 bit PCM WAV file at the sample rate librespot plays at, to interleaved stereo
+fn read_wav(f: &[u8]) -> Result<Vec<f64>, String> {
+    if f.len() < 12 || &f[0..4] != b"RIFF" || &f[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_string());
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([f[i], f[i + 1]]);
+    let mut channels = None;
+    let mut i = 12;
+    // the file is a list of chunks, `fmt ` describes the samples in `data`
+    while i + 8 <= f.len() {
+        let len = u32::from_le_bytes([f[i + 4], f[i + 5], f[i + 6], f[i + 7]]) as usize;
+        let body = &f[i + 8..(i + 8 + len).min(f.len())];
+        match &f[i..i + 4] {
+            b"fmt " if body.len() >= 16 => {
+                let (format, ch) = (u16_at(i + 8), u16_at(i + 10));
+                let rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let bits = u16_at(i + 22);
+                if format != 1 || bits != 16 || !(1..=2).contains(&ch) {
+                    return Err("only 16 bit mono or stereo PCM is supported".to_string());
+                }
+                if rate != SAMPLE_RATE {
+                    return Err(format!(
+                        "the sample rate must be {}Hz, not {}Hz",
+                        SAMPLE_RATE, rate
+                    ));
+                }
+                channels = Some(ch as usize);
+            }
+            b"data" => {
+                let channels = channels.ok_or("the data comes before its format")?;
+                let samples = body
+                    .chunks_exact(2)
+                    .map(|s| i16::from_le_bytes([s[0], s[1]]) as f64 / 32768.0);
+                return Ok(if channels == 1 {
+                    samples.flat_map(|s| [s; NUM_CHANNELS as usize]).collect()
+                } else {
+                    samples.collect()
+                });
+            }
+            _ => {}
+        }
+        // chunks are padded to an even length
+        i += 8 + len + len % 2;
+    }
+    Err("no audio data".to_string())
+}
+
+/// Interleaved stereo samples for the beeps, scaled to `volume` percent
+fn chime_samples(volume: u8) -> Vec<f64> {
+    let rate = SAMPLE_RATE as f64;
+    let level = LEVEL * volume as f64 / 100.0;
+    let mut samples = vec![];
+    for _ in 0..BEEPS {
+        for i in 0..(rate * BEEP_SECS) as usize {
+            let t = i as f64 / rate;
+            // ramp in and out over 10ms so the beep doesn't click
+            let envelope = (t / 0.01).min((BEEP_SECS - t) / 0.01).min(1.0);
+            let sample = (2.0 * PI * CHIME_HZ * t).sin() * envelope * level;
+            samples.extend([sample; NUM_CHANNELS as usize]);
+        }
+        samples.extend(vec![
+            0.0;
+            (rate * GAP_SECS) as usize * NUM_CHANNELS as usize
+        ]);
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chime_follows_the_volume() {
+        let loud = chime_samples(100);
+        let frames = ((SAMPLE_RATE as f64 * BEEP_SECS) as usize
+            + (SAMPLE_RATE as f64 * GAP_SECS) as usize)
+            * BEEPS;
+        assert_eq!(loud.len(), frames * NUM_CHANNELS as usize);
+        let peak = |s: &[f64]| s.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+        assert!(peak(&loud) <= LEVEL && peak(&loud) > LEVEL * 0.9);
+        assert!((peak(&chime_samples(50)) - peak(&loud) / 2.0).abs() < 1e-9);
+        assert_eq!(peak(&chime_samples(0)), 0.0);
+        // it starts silent so it doesn't click
+        assert_eq!(loud[0], 0.0);
+    }
+
+    fn wav(channels: u16, rate: u32, samples: &[i16]) -> Vec<u8> {
+        let data = samples
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let mut f = b"RIFF".to_vec();
+        f.extend((36 + data.len() as u32).to_le_bytes());
+        f.extend(b"WAVEfmt ");
+        f.extend(16u32.to_le_bytes());
+        f.extend(1u16.to_le_bytes());
+        f.extend(channels.to_le_bytes());
+        f.extend(rate.to_le_bytes());
+        f.extend((rate * channels as u32 * 2).to_le_bytes());
+        f.extend((channels * 2).to_le_bytes());
+        f.extend(16u16.to_le_bytes());
+        f.extend(b"data");
+        f.extend((data.len() as u32).to_le_bytes());
+        f.extend(data);
+        f
+    }
+
+    #[test]
+    fn reads_wav_files() {
+        assert_eq!(
+            read_wav(&wav(2, SAMPLE_RATE, &[0, 16384, -32768, 0])).unwrap(),
+            vec![0.0, 0.5, -1.0, 0.0]
+        );
+        // mono is played on both channels
+        assert_eq!(
+            read_wav(&wav(1, SAMPLE_RATE, &[16384, 0])).unwrap(),
+            vec![0.5, 0.5, 0.0, 0.0]
+        );
+        assert!(read_wav(&wav(2, 48000, &[0, 0])).is_err());
+        assert!(read_wav(b"not a wav").is_err());
+        assert_eq!(scale(vec![1.0, -0.5], 50), vec![0.5, -0.25]);
+    }
+}
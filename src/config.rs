@@ -0,0 +1,404 @@
+use chrono::{NaiveDate, Weekday};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::alarm::{
+    normalize_uri, parse_bool, parse_num, parse_percent, parse_volume_profile, source_kind,
+    AlarmSettings,
+};
+use crate::days::DayNames;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// tracks shorter than this are skipped when picking the alarm track (0 = no minimum)
+    pub min_track_secs: u32,
+    /// name of the librespot audio backend to play through (None = librespot's default)
+    pub audio_backend: Option<String>,
+    /// playlist to use for alarms that don't set their own
+    pub default_playlist_uri: String,
+    /// alarms file, or a directory of `*.txt` alarm files (None = ~/.spotify_cache/alarms.txt)
+    pub alarms_path: Option<PathBuf>,
+    /// how many times to retry an alarm that failed to play for a transient reason
+    pub max_retries: u32,
+    /// delay before the first retry, doubled after each one
+    pub retry_initial_secs: u32,
+    /// longest delay between retries
+    pub retry_max_secs: u32,
+    /// refuse to load more alarms than this, in case something generated a runaway alarms file
+    pub max_alarms: u32,
+    /// settings shared by the alarms that name the group, set with `group.NAME.KEY=VALUE`
+    pub groups: HashMap<String, AlarmSettings>,
+    /// connect and load the next alarm's track a minute early so it starts instantly
+    pub soft_start: bool,
+    /// a track still playing a minute after this long is assumed stuck and stopped
+    /// (0 = what's left of the track from where it's playing)
+    pub max_duration_secs: u32,
+    /// stop an alarm that plays on through its playlist after this long (0 = never)
+    pub max_alarm_secs: u32,
+    /// dates when no alarms go off, e.g. a holiday, set with `disable=FROM..TO`
+    pub disabled: Vec<DateRange>,
+    /// alarm volume, in percent. This and the other volume settings are the defaults for
+    /// alarms and groups that don't set their own
+    pub volume: u8,
+    /// raise the volume to `volume` over this long when an alarm starts (0 = no fade)
+    pub fade_in_secs: u32,
+    /// volume in percent the fade starts from, for speakers that are silent at the lowest levels
+    pub fade_min_volume: u8,
+    /// WAV file to play for `pre_chime` alarms instead of the beeps
+    pub chime_file: Option<PathBuf>,
+    /// extra labels for days in alarms, set with `day_names.Mon=Lu,Lun`
+    pub day_names: DayNames,
+    /// an alarm due this soon after another one went off is handled by `min_gap_policy`
+    /// (0 = no gap)
+    pub min_gap_between_triggers_secs: u32,
+    pub min_gap_policy: GapPolicy,
+    /// an alarm won't go off again this soon after it last did, even if it's reloaded
+    /// unplayed (0 = no grace)
+    pub refire_grace_secs: u32,
+    /// set the output device's own (ALSA) volume to this percent while an alarm plays,
+    /// for when it's been left muted or low (None = leave it alone)
+    pub system_volume: Option<u8>,
+    /// ALSA mixer control `system_volume` sets
+    pub system_mixer_control: String,
+    /// put the system volume back how it was once the alarm is over
+    pub restore_system_volume: bool,
+    /// how many times to pick an alarm back up where it was if playback stops unexpectedly
+    pub playback_retries: u32,
+    /// streaming quality in kbit/s: 96, 160 or 320
+    pub bitrate: u32,
+    /// let librespot even out the loudness of tracks
+    pub normalisation: bool,
+    /// load the track this long before starting it, so a slow connection doesn't stutter
+    pub prebuffer_secs: u32,
+    /// `(seconds in, volume)` points the alarm's volume moves between, instead of `volume`
+    /// and the fade (empty = use those)
+    pub volume_profile: Vec<(u32, u8)>,
+    /// a wall clock change of more than this between loops, e.g. from NTP, resets the
+    /// scheduler's record of when alarms went off (0 = never)
+    pub clock_jump_secs: u32,
+    /// don't pick any of the last this many tracks played from a playlist, unless there's
+    /// nothing else to play (0 = allow repeats)
+    pub avoid_repeats: u32,
+}
+
+/// What happens to an alarm that's due too soon after another one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GapPolicy {
+    /// don't play it today
+    Skip,
+    /// play it once the gap has passed
+    Queue,
+}
+
+/// The days from `from` to `to`, inclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateRange {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.from <= date && date <= self.to
+    }
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_track_secs: 0,
+            audio_backend: None,
+            default_playlist_uri: "spotify:playlist:2aBMj4vGrpxavecIWQtcc4".to_string(),
+            alarms_path: None,
+            max_retries: 3,
+            retry_initial_secs: 5,
+            retry_max_secs: 60,
+            max_alarms: 1000,
+            groups: HashMap::new(),
+            soft_start: false,
+            max_duration_secs: 0,
+            max_alarm_secs: 3600,
+            disabled: vec![],
+            volume: 100,
+            fade_in_secs: 0,
+            fade_min_volume: 0,
+            chime_file: None,
+            day_names: DayNames::default(),
+            min_gap_between_triggers_secs: 0,
+            min_gap_policy: GapPolicy::Skip,
+            refire_grace_secs: 90,
+            system_volume: None,
+            system_mixer_control: "PCM".to_string(),
+            restore_system_volume: false,
+            playback_retries: 2,
+            bitrate: 160,
+            normalisation: false,
+            prebuffer_secs: 0,
+            volume_profile: vec![],
+            clock_jump_secs: 120,
+            avoid_repeats: 5,
+        }
+    }
+}
+impl Config {
+    /// How long to wait before retry number `attempt` (starting at 0)
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let secs = (self.retry_initial_secs as u64)
+            .saturating_mul(2u64.saturating_pow(attempt))
+            .min(self.retry_max_secs as u64);
+        Duration::from_secs(secs)
+    }
+
+    /// The disabled range `date` falls in, if any
+    pub fn disabled_on(&self, date: NaiveDate) -> Option<&DateRange> {
+        self.disabled.iter().find(|r| r.contains(date))
+    }
+}
+
+/// Parse the config file. Each line is `key=value`, lines starting with `#` are comments.
+/// Keys that aren't set keep their default value.
+pub fn get_config(f: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for (i, line) in f.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        set_key(&mut config, line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+    }
+    check_fade(config.volume, config.fade_in_secs, config.fade_min_volume)?;
+    Ok(config)
+}
+
+/// A fade has to go up, for the config's defaults and each alarm's own settings
+pub fn check_fade(volume: u8, fade_in_secs: u32, fade_min_volume: u8) -> Result<(), String> {
+    if fade_in_secs > 0 && fade_min_volume >= volume {
+        return Err(format!(
+            "fade_min_volume ({}) must be less than volume ({})",
+            fade_min_volume, volume
+        ));
+    }
+    Ok(())
+}
+
+/// Apply one `key=value` line to the config
+fn set_key(config: &mut Config, line: &str) -> Result<(), String> {
+    let Some((key, value)) = line.split_once('=') else {
+        return Err(format!("expected key=value, got \"{}\"", line));
+    };
+    let value = value.trim();
+    match key.trim() {
+        "min_track_secs" => config.min_track_secs = parse_num(key, value)?,
+        "audio_backend" => config.audio_backend = Some(value.to_string()),
+        "default_playlist_uri" => {
+            let uri = normalize_uri(value);
+            if source_kind(&uri).is_none() {
+                return Err(format!("invalid default_playlist_uri \"{}\"", value));
+            }
+            config.default_playlist_uri = uri
+        }
+        "alarms_path" => config.alarms_path = Some(PathBuf::from(value)),
+        "max_retries" => config.max_retries = parse_num(key, value)?,
+        "retry_initial_secs" => config.retry_initial_secs = parse_num(key, value)?,
+        "retry_max_secs" => config.retry_max_secs = parse_num(key, value)?,
+        "max_alarms" => config.max_alarms = parse_num(key, value)?,
+        "soft_start" => config.soft_start = parse_bool(key.trim(), value)?,
+        "max_duration_secs" => config.max_duration_secs = parse_num(key, value)?,
+        "max_alarm_secs" => config.max_alarm_secs = parse_num(key, value)?,
+        // can be given more than once
+        "disable" => config.disabled.push(parse_date_range(value)?),
+        "volume" => config.volume = parse_percent(key, value)?,
+        "fade_in_secs" => config.fade_in_secs = parse_num(key, value)?,
+        "fade_min_volume" => config.fade_min_volume = parse_percent(key, value)?,
+        "chime_file" => config.chime_file = Some(PathBuf::from(value)),
+        "system_volume" if !cfg!(target_os = "linux") => {
+            return Err("system_volume isn't supported here, it needs ALSA (Linux)".to_string())
+        }
+        "system_volume" => config.system_volume = Some(parse_percent(key, value)?),
+        "system_mixer_control" => config.system_mixer_control = value.to_string(),
+        "restore_system_volume" => config.restore_system_volume = parse_bool(key.trim(), value)?,
+        "playback_retries" => config.playback_retries = parse_num(key, value)?,
+        "bitrate" => {
+            config.bitrate = match parse_num(key, value)? {
+                b @ (96 | 160 | 320) => b,
+                _ => return Err(format!("bitrate must be 96, 160 or 320, got {}", value)),
+            }
+        }
+        "normalisation" => config.normalisation = parse_bool(key.trim(), value)?,
+        "prebuffer_secs" => config.prebuffer_secs = parse_num(key, value)?,
+        "volume_profile" => config.volume_profile = parse_volume_profile(value)?,
+        "clock_jump_secs" => config.clock_jump_secs = parse_num(key, value)?,
+        "avoid_repeats" => config.avoid_repeats = parse_num(key, value)?,
+        "min_gap_between_triggers_secs" => {
+            config.min_gap_between_triggers_secs = parse_num(key, value)?
+        }
+        "refire_grace_secs" => config.refire_grace_secs = parse_num(key, value)?,
+        "min_gap_policy" => {
+            config.min_gap_policy = match value {
+                "skip" => GapPolicy::Skip,
+                "queue" => GapPolicy::Queue,
+                _ => {
+                    return Err(format!(
+                        "min_gap_policy must be skip or queue, got \"{}\"",
+                        value
+                    ))
+                }
+            }
+        }
+        key if key.starts_with("day_names.") => {
+            let day = key["day_names.".len()..].parse::<Weekday>().map_err(|_| {
+                format!(
+                    "expected day_names.DAY, e.g. day_names.Mon, got \"{}\"",
+                    key
+                )
+            })?;
+            for label in value.split(',') {
+                config.day_names.add(label.trim(), day)?;
+            }
+        }
+        key if key.starts_with("group.") => {
+            let Some((name, key)) = key["group.".len()..].split_once('.') else {
+                return Err(format!("expected group.NAME.KEY, got \"{}\"", key));
+            };
+            config
+                .groups
+                .entry(name.to_string())
+                .or_default()
+                .set(key, value)?;
+        }
+        _ => return Err(format!("unknown config key \"{}\"", key.trim())),
+    }
+    Ok(())
+}
+
+/// Parse `YYYY-MM-DD..YYYY-MM-DD`, or a single `YYYY-MM-DD`
+fn parse_date_range(value: &str) -> Result<DateRange, String> {
+    let parse = |d: &str| {
+        d.trim()
+            .parse::<NaiveDate>()
+            .map_err(|_| format!("invalid date \"{}\", expected YYYY-MM-DD", d))
+    };
+    let (from, to) = value.split_once("..").unwrap_or((value, value));
+    let range = DateRange {
+        from: parse(from)?,
+        to: parse(to)?,
+    };
+    if range.from > range.to {
+        return Err(format!("disable range \"{}\" ends before it starts", value));
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_config() {
+        let config = "# skip intro tracks
+min_track_secs = 90
+audio_backend=pipe
+default_playlist_uri=spotify:playlist:37i9dQZF1DX0UrRvztWcAU
+max_alarms=10
+group.weekday.playlists=spotify:playlist:37i9dQZF1DX0UrRvztWcAU
+group.weekday.resume=true
+";
+        let config = get_config(config).unwrap();
+        assert_eq!(config.min_track_secs, 90);
+        assert_eq!(config.max_alarms, 10);
+        assert_eq!(config.groups["weekday"].resume, Some(true));
+        assert_eq!(config.groups["weekday"].playlists.len(), 1);
+        assert_eq!(config.audio_backend, Some("pipe".to_string()));
+        assert_eq!(
+            config.default_playlist_uri,
+            "spotify:playlist:37i9dQZF1DX0UrRvztWcAU"
+        );
+        assert_eq!(get_config("").unwrap(), Config::default());
+        assert!(get_config("min_track_secs=abc").is_err());
+        assert!(get_config("nope=1").is_err());
+        assert!(get_config("group.weekday=1").is_err());
+        assert_eq!(
+            get_config("group.weekday.volume=1").unwrap().groups["weekday"].volume,
+            Some(1)
+        );
+        assert!(get_config("group.weekday.volume=101").is_err());
+        assert_eq!(
+            get_config("default_playlist_uri=2aBMj4vGrpxavecIWQtcc4")
+                .unwrap()
+                .default_playlist_uri,
+            "spotify:playlist:2aBMj4vGrpxavecIWQtcc4"
+        );
+        assert!(get_config("default_playlist_uri=spotify:track:5PbMSJZcNA3p2LZv7C56cm").is_err());
+        assert_eq!(
+            get_config("# comment\nvolume=50\nmax_alarms=x").unwrap_err(),
+            "line 3: max_alarms must be a whole number, got \"x\""
+        );
+        assert!(get_config("volume=101").is_err());
+        assert!(get_config("fade_in_secs=30\nvolume=50\nfade_min_volume=50").is_err());
+        assert!(get_config("volume=50\nfade_min_volume=50").is_ok());
+        assert_eq!(
+            get_config("min_gap_policy=queue").unwrap().min_gap_policy,
+            GapPolicy::Queue
+        );
+        assert!(get_config("min_gap_policy=later").is_err());
+        assert_eq!(
+            get_config("refire_grace_secs=5").unwrap().refire_grace_secs,
+            5
+        );
+        assert!(get_config("refire_grace_secs=-1").is_err());
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                get_config("system_volume=80").unwrap().system_volume,
+                Some(80)
+            );
+        } else {
+            assert!(get_config("system_volume=80").is_err());
+        }
+        assert!(get_config("system_volume=loud").is_err());
+        assert_eq!(get_config("bitrate=320").unwrap().bitrate, 320);
+        assert_eq!(Config::default().max_alarm_secs, 3600);
+        assert_eq!(get_config("max_alarm_secs=0").unwrap().max_alarm_secs, 0);
+        assert!(get_config("bitrate=128").is_err());
+        assert_eq!(
+            get_config("volume_profile=0:10, 60:90")
+                .unwrap()
+                .volume_profile,
+            vec![(0, 10), (60, 90)]
+        );
+        assert!(get_config("volume_profile=60:90,30:10").is_err());
+        assert!(get_config("volume_profile=0:110").is_err());
+        assert!(get_config("volume_profile=loud").is_err());
+    }
+    #[test]
+    fn retry_delay_backs_off() {
+        let config = get_config("retry_initial_secs=5\nretry_max_secs=30").unwrap();
+        let delays = (0..5)
+            .map(|i| config.retry_delay(i).as_secs())
+            .collect::<Vec<u64>>();
+        assert_eq!(delays, vec![5, 10, 20, 30, 30]);
+        assert_eq!(config.retry_delay(100).as_secs(), 30);
+    }
+    #[test]
+    fn disabled_date_ranges() {
+        let config = get_config("disable=2024-12-24..2025-01-02\ndisable=2025-03-01").unwrap();
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert!(config.disabled_on(date(2024, 12, 23)).is_none());
+        assert!(config.disabled_on(date(2024, 12, 24)).is_some());
+        assert!(config.disabled_on(date(2025, 1, 2)).is_some());
+        assert!(config.disabled_on(date(2025, 1, 3)).is_none());
+        assert!(config.disabled_on(date(2025, 3, 1)).is_some());
+        assert!(get_config("disable=2025-01-02..2024-12-24").is_err());
+        assert!(get_config("disable=Dec 24").is_err());
+    }
+    #[test]
+    fn day_names() {
+        let config = get_config("day_names.Mon=Lu,Lun\nday_names.sunday=Di").unwrap();
+        assert_eq!(config.day_names.parse("Lun"), Some(Weekday::Mon));
+        assert_eq!(config.day_names.parse("Di"), Some(Weekday::Sun));
+        let alarms = crate::alarm::get_alarms("7:00 Lu,Di,W weekly", &config.day_names).unwrap();
+        assert_eq!(
+            alarms[0].days,
+            vec![Weekday::Mon, Weekday::Sun, Weekday::Wed]
+        );
+        assert!(get_config("day_names.Someday=X").is_err());
+    }
+}
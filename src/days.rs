@@ -0,0 +1,84 @@
+use chrono::Weekday;
+
+/// The labels used for days in alarms. The built in short labels (`M,T,W,Th,F,S,Su`) always
+/// work, and the config can add more, e.g. in another language.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DayNames {
+    labels: Vec<(String, Weekday)>,
+}
+impl DayNames {
+    /// Add `label` as another name for `day`
+    pub fn add(&mut self, label: &str, day: Weekday) -> Result<(), String> {
+        if let Some(other) = self.parse(label).filter(|d| *d != day) {
+            return Err(format!(
+                "day label \"{}\" is already used for {}",
+                label, other
+            ));
+        }
+        self.labels.push((label.to_string(), day));
+        Ok(())
+    }
+
+    pub fn parse(&self, label: &str) -> Option<Weekday> {
+        to_weekday(label).or_else(|| {
+            self.labels
+                .iter()
+                .find(|(l, _)| l == label)
+                .map(|(_, d)| *d)
+        })
+    }
+
+    /// The label to show for `day`, the first one the config added or else the built in one
+    pub fn format(&self, day: Weekday) -> String {
+        self.labels
+            .iter()
+            .find(|(_, d)| *d == day)
+            .map_or_else(|| from_weekday(day).to_string(), |(l, _)| l.clone())
+    }
+}
+
+fn to_weekday(d: &str) -> Option<Weekday> {
+    match d {
+        "M" => Some(Weekday::Mon),
+        "T" => Some(Weekday::Tue),
+        "W" => Some(Weekday::Wed),
+        "Th" => Some(Weekday::Thu),
+        "F" => Some(Weekday::Fri),
+        "S" => Some(Weekday::Sat),
+        "Su" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+fn from_weekday(d: Weekday) -> &'static str {
+    match d {
+        Weekday::Mon => "M",
+        Weekday::Tue => "T",
+        Weekday::Wed => "W",
+        Weekday::Thu => "Th",
+        Weekday::Fri => "F",
+        Weekday::Sat => "S",
+        Weekday::Sun => "Su",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn localized_day_names() {
+        let mut days = DayNames::default();
+        days.add("Lu", Weekday::Mon).unwrap();
+        days.add("Sa", Weekday::Sat).unwrap();
+        assert_eq!(days.parse("Lu"), Some(Weekday::Mon));
+        assert_eq!(days.parse("M"), Some(Weekday::Mon));
+        assert_eq!(days.parse("Sa"), Some(Weekday::Sat));
+        assert_eq!(days.parse("Xx"), None);
+        assert_eq!(days.format(Weekday::Mon), "Lu");
+        assert_eq!(days.format(Weekday::Thu), "Th");
+        // the built in labels can't be taken over
+        assert!(days.add("M", Weekday::Tue).is_err());
+        assert!(days.add("Lu", Weekday::Fri).is_err());
+    }
+}
@@ -1,20 +1,133 @@
 use crate::util::get_home_path;
 use chrono::prelude::*;
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::path::Path;
 use std::{fs, process::exit};
-use tokio::time::{sleep, Duration};
+use tokio::time::{Duration, Instant};
 mod alarm;
+mod chime;
+mod config;
+mod days;
+mod scheduler;
 mod spotify;
+mod state;
 mod util;
 
 const ALARMS_FILE_NAME: &str = "alarms.txt";
+const CONFIG_FILE_NAME: &str = "config.txt";
+
+fn version() -> String {
+    let built = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .map_or("unknown".to_string(), |t| t.to_rfc3339());
+    format!(
+        "{} {} (commit {}, built {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_COMMIT"),
+        built
+    )
+}
 
 #[tokio::main]
 async fn main() {
-    let mut alarms_file = get_home_path().unwrap();
-    alarms_file.push(ALARMS_FILE_NAME);
+    // printed at startup too, so the logs show which build was running
+    println!("{}", version());
+    util::init_logging();
+    if util::has_flag("--version") {
+        return;
+    }
+
+    // the config file is optional, everything has a default. `--config -` reads it from stdin
+    let (config_name, config_text) = match util::option_arg("--config").as_deref() {
+        Some("-") => {
+            let mut f = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut f) {
+                eprintln!("Unable to read the config from stdin: {}", e);
+                exit(1);
+            }
+            ("stdin".to_string(), Some(f))
+        }
+        Some(path) => match fs::read_to_string(path) {
+            Ok(f) => (path.to_string(), Some(f)),
+            Err(e) => {
+                eprintln!("Unable to read {}: {}", path, e);
+                exit(1);
+            }
+        },
+        None => {
+            let mut config_file = get_home_path().unwrap();
+            config_file.push(CONFIG_FILE_NAME);
+            let f = match fs::read_to_string(&config_file) {
+                Ok(f) => Some(f),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    eprintln!("Unable to read {}: {}", config_file.to_str().unwrap(), e);
+                    exit(1);
+                }
+            };
+            (config_file.to_str().unwrap().to_string(), f)
+        }
+    };
+    let config = match config_text.as_deref().map(config::get_config) {
+        Some(Ok(c)) => c,
+        Some(Err(e)) => {
+            eprintln!("Invalid config in {}: {}", config_name, e);
+            exit(1);
+        }
+        None => config::Config::default(),
+    };
+    // show what's actually in effect once defaults are filled in
+    println!("{:?}", config);
+
+    // find out now if the alarms would be silent rather than the morning they go off
+    match spotify::check_audio(&config) {
+        Ok(()) => println!("Audio ready"),
+        Err(e) => eprintln!("WARNING: alarms won't be audible, {}", e),
+    }
+    if util::has_flag("--ping") {
+        match spotify::ping(&config).await {
+            Ok(d) => println!("Spotify responded in {}ms", d.as_millis()),
+            Err(e) => {
+                eprintln!("Unable to reach Spotify: {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+    if util::has_flag("--selftest") {
+        match spotify::selftest(&config).await {
+            Ok(()) => println!("Self test passed, alarms can play"),
+            Err(e) => {
+                eprintln!("Self test failed: {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    let alarms_file = config.alarms_path.clone().unwrap_or_else(|| {
+        let mut alarms_file = get_home_path().unwrap();
+        alarms_file.push(ALARMS_FILE_NAME);
+        alarms_file
+    });
+
+    if let Some((command, args)) = util::command() {
+        match command.as_str() {
+            "fire" => fire(&alarms_file, &args, &config).await,
+            "explain" => explain(&alarms_file, &args, &config),
+            "reset" => reset(&alarms_file, &args, &config),
+            "next" => next(&alarms_file, &args, &config),
+            _ => unreachable!(),
+        }
+        return;
+    }
 
     // check that the file exists
-    if let Err(_) = fs::metadata(&alarms_file) {
+    if fs::metadata(&alarms_file).is_err() {
         eprintln!(
             "\n{} didn't exist. Please populate it.\nEx: Time Days Desc\n6:00 M,T,W,Th,F,S,Su My first alarm",
             alarms_file.to_str().unwrap()
@@ -24,23 +137,66 @@ async fn main() {
         exit(1);
     }
 
+    let mut scheduler = scheduler::Scheduler::new(spotify::Spotify);
     let mut first = true;
     let mut alarms = vec![];
+    // the last alarms that loaded, all of them and not just today's, to fall back on while
+    // the file is invalid
+    let mut loaded = vec![];
+    let mut alarms_err: Option<String> = None;
+    let mut last_tick: Option<(DateTime<Local>, Instant)> = None;
+    // alarms that went off but couldn't be recorded as played, so they aren't played again
+    let mut unsaved = HashSet::new();
     loop {
-        // get the alarms from the file
-        let f = fs::read_to_string(&alarms_file).expect(
-            format!(
-                "There was a problem reading {}",
-                alarms_file.to_str().unwrap()
-            )
-            .as_str(),
-        );
-        let my_alarms = alarm::get_alarms(f.as_str()).unwrap();
+        // get the alarms from the file (or directory of files)
+        let my_alarms = match alarm::load_alarms(&alarms_file, &config.day_names)
+            .and_then(|a| alarm::check_alarms(a, &config))
+        {
+            Ok(a) => {
+                alarms_err = None;
+                loaded = a.clone();
+                a
+            }
+            Err(e) => {
+                // keep the alarms we had until the file is fixed
+                if first {
+                    eprintln!("Invalid alarms: {}", e);
+                    exit(1);
+                } else if alarms_err.as_ref() != Some(&e) {
+                    eprintln!("Invalid alarms: {}", e);
+                }
+                alarms_err = Some(e);
+                loaded.clone()
+            }
+        };
 
         // figure out which alarm should be next -- specifically which alarms should run today and
         // which alarms have already run (merge current state with new state)
         let time = Local::now();
+        let now = Instant::now();
+        if let Some((last_time, last_now)) = last_tick {
+            if scheduler::clock_jump(time - last_time, now - last_now, config.clock_jump_secs) {
+                println!(
+                    "The clock jumped from {} to {}, resetting the scheduler",
+                    last_time.to_rfc3339(),
+                    time.to_rfc3339()
+                );
+                scheduler.clock_jumped();
+            }
+        }
+        last_tick = Some((time, now));
         alarms = alarm::get_valid_alarms(my_alarms, alarms, time);
+        // the played file is the record of what went off today, from before a restart or
+        // `fire`, and `reset` clearing it takes effect straight away. Broken alarms stay played.
+        let played = state::get_played(time.date_naive());
+        for a in alarms.iter_mut().filter(|a| !a.broken) {
+            let key = a.key();
+            let was_played = a.played;
+            a.played = played.contains(&key) || unsaved.contains(&(time.date_naive(), key.clone()));
+            if was_played && !a.played {
+                scheduler.forget(&key);
+            }
+        }
         if first {
             first = false;
             for a in alarms.iter() {
@@ -50,14 +206,133 @@ async fn main() {
         }
 
         // check if any alarms need to be playing
-        for a in alarms.iter_mut() {
-            if a.should_play(time) {
-                println!("> {:?}", a);
-                println!("@ {:?}", time);
-                a.played = true;
-                spotify::play_alarm().await;
+        let was_played = alarms.iter().map(|a| a.played).collect::<Vec<bool>>();
+        scheduler.tick(&mut alarms, time, &config).await;
+        for (a, was_played) in alarms.iter().zip(was_played) {
+            if a.played && !was_played {
+                if let Err(e) = state::set_played(&a.key(), time.date_naive()) {
+                    eprintln!("{}", e);
+                    unsaved.insert((time.date_naive(), a.key()));
+                }
+            }
+        }
+        // an alarm that went off plays while we wait for the next tick
+        scheduler.run_for(Duration::from_secs(1)).await;
+    }
+}
+
+/// `fire NAME`: play the alarm described as NAME once, then exit
+async fn fire(alarms_file: &Path, args: &[String], config: &config::Config) {
+    let a = find_alarm(alarms_file, "fire", args, config);
+    let time = Local::now();
+    // once it's due it counts as today's, so a running alarm clock doesn't play it again.
+    // Trying it out earlier than that leaves today's alarm to go off
+    if a.stands_in_for_today(time, config.refire_grace_secs) {
+        if let Err(e) = state::set_played(&a.key(), time.date_naive()) {
+            eprintln!("{}", e);
+        }
+    }
+    let mut scheduler = scheduler::Scheduler::new(spotify::Spotify);
+    scheduler.fire(&a, time, config).await;
+    scheduler.wait().await;
+}
+
+/// `explain NAME`: say when the alarm described as NAME goes off, and why it doesn't today
+fn explain(alarms_file: &Path, args: &[String], config: &config::Config) {
+    let a = find_alarm(alarms_file, "explain", args, config);
+    let time = Local::now();
+    let played = state::get_played(time.date_naive()).contains(&a.key());
+    for line in a.explain(time, played, config) {
+        println!("{}", line);
+    }
+}
+
+/// `next NAME [COUNT]`: list the next COUNT (5) times the alarm described as NAME goes off
+fn next(alarms_file: &Path, args: &[String], config: &config::Config) {
+    let (name, count) = match args {
+        [name] => (name, 5),
+        [name, count] => match count.parse::<usize>() {
+            Ok(count) => (name, count),
+            Err(_) => {
+                eprintln!("Usage: next NAME [COUNT]");
+                exit(1);
             }
+        },
+        _ => {
+            eprintln!("Usage: next NAME [COUNT]");
+            exit(1);
+        }
+    };
+    let a = find_alarm(alarms_file, "next", std::slice::from_ref(name), config);
+    let time = Local::now();
+    let played = state::get_played(time.date_naive()).contains(&a.key());
+    let mut any = false;
+    for t in a.occurrences(time, played, config).take(count) {
+        println!("{}", t.format("%a %Y-%m-%d %H:%M:%S"));
+        any = true;
+    }
+    if !any {
+        println!("{:?} won't go off in the next year", a.desc);
+    }
+}
+
+/// `reset [NAME]`: forget that the alarm described as NAME (or every alarm) went off today
+fn reset(alarms_file: &Path, args: &[String], config: &config::Config) {
+    let key = match args {
+        [] => None,
+        _ => Some(find_alarm(alarms_file, "reset", args, config).key()),
+    };
+    match state::clear_played(key.as_deref(), Local::now().date_naive()) {
+        Ok(n) => println!("Forgot {} alarm(s) going off today", n),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// The alarm named by a command's only argument, exiting with a usage error if there isn't one
+fn find_alarm(
+    alarms_file: &Path,
+    command: &str,
+    args: &[String],
+    config: &config::Config,
+) -> alarm::Alarm {
+    let [name] = args else {
+        eprintln!("Usage: {} NAME", command);
+        exit(1);
+    };
+    let alarms = match alarm::load_alarms(alarms_file, &config.day_names)
+        .and_then(|a| alarm::check_alarms(a, config))
+    {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("Invalid alarms: {}", e);
+            exit(1);
+        }
+    };
+    let found = alarms
+        .iter()
+        .filter(|a| a.desc == *name)
+        .collect::<Vec<_>>();
+    match found.as_slice() {
+        [a] => (*a).clone(),
+        [] => {
+            let names = alarms
+                .iter()
+                .map(|a| format!("{:?}", a.desc))
+                .collect::<Vec<String>>()
+                .join(", ");
+            eprintln!("No alarm called {:?}. The alarms are: {}", name, names);
+            exit(1);
+        }
+        _ => {
+            eprintln!(
+                "{} alarms are called {:?}, rename one of them",
+                found.len(),
+                name
+            );
+            exit(1);
         }
-        sleep(Duration::from_secs(1)).await;
     }
 }
@@ -0,0 +1,760 @@
+use chrono::{DateTime, Local, Timelike};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::exit;
+use std::rc::Rc;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::alarm::{Action, Alarm};
+use crate::config::{Config, GapPolicy};
+use crate::spotify::{Play, PlayError, PlayRequest, Stop};
+
+/// How long before an alarm a soft start loads its track
+const PREWARM_SECS: i64 = 60;
+/// How long an alarm that's taken over from gets to stop and clean up
+const STOP_TIMEOUT_SECS: u64 = 10;
+
+/// An alarm whose track has been loaded ahead of time, as the alarm was when it was loaded
+struct Prewarmed<T> {
+    alarm: Alarm,
+    prepared: T,
+}
+
+/// An alarm that's going off, which plays while the scheduler waits between ticks
+struct Playing {
+    desc: String,
+    alarm: Pin<Box<dyn Future<Output = ()>>>,
+    stop: watch::Sender<bool>,
+}
+
+pub struct Scheduler<P: Play> {
+    pub player: Rc<P>,
+    prewarmed: Option<Prewarmed<P::Prepared>>,
+    /// the last alarm we tried to pre-warm, so a failure isn't retried every tick
+    prewarm_tried: Option<Alarm>,
+    /// when each alarm (by key) last went off
+    fired: HashMap<String, DateTime<Local>>,
+    /// when any alarm last went off, for `min_gap_between_triggers_secs`
+    last_fired: Option<DateTime<Local>>,
+    /// alarms waiting for the gap after the last one to pass
+    queued: Vec<Alarm>,
+    /// the alarm that's going off
+    playing: Option<Playing>,
+}
+impl<P: Play + 'static> Scheduler<P> {
+    pub fn new(player: P) -> Self {
+        Scheduler {
+            player: Rc::new(player),
+            prewarmed: None,
+            prewarm_tried: None,
+            fired: HashMap::new(),
+            last_fired: None,
+            queued: vec![],
+            playing: None,
+        }
+    }
+
+    /// Forget when alarms went off and anything preloaded, after the wall clock jumped. Those
+    /// were timed by the old clock, so the refire grace and `min_gap_between_triggers_secs`
+    /// would be measured against the wrong times. Played flags are kept, so nothing plays twice,
+    /// and so are queued alarms, which are already marked played and would otherwise be lost.
+    pub fn clock_jumped(&mut self) {
+        self.fired.clear();
+        self.last_fired = None;
+        self.prewarmed = None;
+        self.prewarm_tried = None;
+    }
+
+    /// Forget that the alarm with `key` went off, after `reset`, so the refire grace doesn't
+    /// stop it going off again
+    pub fn forget(&mut self, key: &str) {
+        self.fired.remove(key);
+    }
+
+    pub async fn tick(&mut self, alarms: &mut [Alarm], time: DateTime<Local>, config: &Config) {
+        if let Some(range) = config.disabled_on(time.date_naive()) {
+            for a in alarms.iter_mut().filter(|a| a.should_play(time)) {
+                println!(
+                    "Skipping {:?}, alarms are disabled until {}",
+                    a.desc, range.to
+                );
+                a.played = true;
+            }
+            return;
+        }
+        if config.soft_start {
+            self.prewarm(alarms, time, config).await;
+        }
+        self.fire_due_alarms(alarms, time, config).await;
+    }
+
+    /// Let the alarm that's going off, if any, play for up to `duration`. Playing only
+    /// happens in here, so the scheduler keeps checking for due alarms while one plays.
+    pub async fn run_for(&mut self, duration: Duration) {
+        let until = sleep(duration);
+        tokio::pin!(until);
+        if let Some(playing) = &mut self.playing {
+            let finished = tokio::select! {
+                _ = &mut playing.alarm => true,
+                _ = &mut until => false,
+            };
+            if !finished {
+                return;
+            }
+            self.playing = None;
+        }
+        until.await;
+    }
+
+    /// Play the alarm that's going off, if any, to the end
+    pub async fn wait(&mut self) {
+        if let Some(playing) = self.playing.take() {
+            playing.alarm.await;
+        }
+    }
+
+    /// Load the track for the alarm about to go off, and throw it away again if that alarm
+    /// is removed or edited before it fires
+    async fn prewarm(&mut self, alarms: &[Alarm], time: DateTime<Local>, config: &Config) {
+        if let Some(p) = &self.prewarmed {
+            if !alarms.iter().any(|a| !a.played && same_alarm(a, &p.alarm)) {
+                println!("Discarding the preloaded track for {:?}", p.alarm.desc);
+                self.prewarmed = None;
+            }
+        }
+        if self.prewarmed.is_some() {
+            return;
+        }
+
+        let Some(next) = alarms.iter().find(|a| {
+            if a.played {
+                return false;
+            }
+            let until = a.time_at(time.date_naive()) - time.time();
+            until > chrono::Duration::zero() && until.num_seconds() <= PREWARM_SECS
+        }) else {
+            return;
+        };
+        if self
+            .prewarm_tried
+            .as_ref()
+            .is_some_and(|a| same_alarm(a, next))
+        {
+            return;
+        }
+        self.prewarm_tried = Some(next.clone());
+
+        let req = PlayRequest::for_alarm(config, next, &mut rand::thread_rng());
+        if req.action == Action::Notify {
+            return;
+        }
+        match self.player.prepare(config, &req).await {
+            Ok(prepared) => {
+                self.prewarmed = Some(Prewarmed {
+                    alarm: next.clone(),
+                    prepared,
+                })
+            }
+            Err(e) => eprintln!(
+                "Unable to preload the alarm, it will load when it fires: {}",
+                e
+            ),
+        }
+    }
+
+    /// Play every alarm that is due at `time` and mark it as played
+    pub async fn fire_due_alarms(
+        &mut self,
+        alarms: &mut [Alarm],
+        time: DateTime<Local>,
+        config: &Config,
+    ) {
+        // a queued alarm also waits for the one before it to finish
+        if !self.queued.is_empty() && !self.in_gap(time, config) && self.playing.is_none() {
+            let a = self.queued.remove(0);
+            self.last_fired = Some(time);
+            self.fire(&a, time, config).await;
+        }
+        for a in alarms.iter_mut() {
+            if a.should_play(time) {
+                a.played = true;
+                let last = self.fired.insert(a.key(), time);
+                let grace = config.refire_grace_secs as i64;
+                if last.is_some_and(|t| (time - t).num_seconds().abs() < grace) {
+                    println!("Not playing {:?} again, it just went off", a.desc);
+                    continue;
+                }
+                // alarms due in the same minute don't cut each other off
+                let same_minute = self.playing.is_some()
+                    && self.last_fired.is_some_and(|t| {
+                        (t.date_naive(), t.hour(), t.minute())
+                            == (time.date_naive(), time.hour(), time.minute())
+                    });
+                if same_minute || self.in_gap(time, config) || !self.queued.is_empty() {
+                    match config.min_gap_policy {
+                        GapPolicy::Skip => {
+                            println!("Skipping {:?}, another alarm just went off", a.desc)
+                        }
+                        GapPolicy::Queue => {
+                            println!("Delaying {:?}, another alarm just went off", a.desc);
+                            self.queued.push(a.clone());
+                        }
+                    }
+                    continue;
+                }
+                self.last_fired = Some(time);
+                self.fire(a, time, config).await;
+            }
+        }
+    }
+
+    /// Whether an alarm went off less than `min_gap_between_triggers_secs` before `time`
+    fn in_gap(&self, time: DateTime<Local>, config: &Config) -> bool {
+        self.last_fired.is_some_and(|t| {
+            (time - t).num_seconds().abs() < config.min_gap_between_triggers_secs as i64
+        })
+    }
+
+    /// Start one alarm going off now, the way it does when it's due. It plays in `run_for`
+    /// or `wait`, once whatever alarm was still playing has stopped.
+    pub async fn fire(&mut self, a: &Alarm, time: DateTime<Local>, config: &Config) {
+        println!("> {:?}", a);
+        println!("@ {}", time.to_rfc3339());
+
+        let req = PlayRequest::for_alarm(config, a, &mut rand::thread_rng());
+        if req.action == Action::Notify {
+            println!("Reminder: {}", a.desc);
+            return;
+        }
+        if let Some(playing) = self.playing.take() {
+            println!("Stopping {:?}, {:?} is going off", playing.desc, a.desc);
+            // the volume is put back before the next alarm sets it
+            let _ = playing.stop.send(true);
+            let stopping = timeout(Duration::from_secs(STOP_TIMEOUT_SECS), playing.alarm);
+            if stopping.await.is_err() {
+                eprintln!("{:?} didn't stop in time", playing.desc);
+            }
+        }
+        let prepared = self
+            .prewarmed
+            .take()
+            .filter(|p| same_alarm(a, &p.alarm))
+            .map(|p| p.prepared);
+        let (player, config) = (Rc::clone(&self.player), config.clone());
+        let (stop, stopped) = Stop::channel();
+        let alarm = async move { play_alarm(&*player, &config, &req, prepared, &stopped).await };
+        self.playing = Some(Playing {
+            desc: a.desc.clone(),
+            alarm: Box::pin(alarm),
+            stop,
+        });
+    }
+}
+
+/// Play an alarm, from its preloaded track if there is one
+async fn play_alarm<P: Play>(
+    player: &P,
+    config: &Config,
+    req: &PlayRequest,
+    prepared: Option<P::Prepared>,
+    stop: &Stop,
+) {
+    // the chime finishes before the music is started, so they never overlap.
+    // A preloaded track starts straight after it, otherwise there's a gap
+    // while Spotify connects
+    if req.pre_chime {
+        if let Err(e) = player.chime(config, req.volume).await {
+            eprintln!("Unable to play the chime: {}", e);
+        }
+    }
+    if let Some(prepared) = prepared {
+        match player.start(config, prepared, stop).await {
+            Ok(()) => return,
+            Err(e) => eprintln!("Unable to play the preloaded alarm: {}", e),
+        }
+    }
+    play_with_retry(config, req, player, stop).await;
+}
+
+/// Whether the wall clock moved more than `max_secs` away from the `elapsed` monotonic time
+/// between two readings, e.g. NTP corrected it (0 = never)
+pub fn clock_jump(wall: chrono::Duration, elapsed: std::time::Duration, max_secs: u32) -> bool {
+    let drift = wall.num_milliseconds() - elapsed.as_millis() as i64;
+    max_secs > 0 && drift.abs() > max_secs as i64 * 1000
+}
+
+/// Alarm equality ignores settings (so an edit keeps the played flag), but a preloaded track
+/// is only good if nothing about the alarm has changed
+fn same_alarm(a: &Alarm, b: &Alarm) -> bool {
+    a == b && a.group == b.group && a.settings == b.settings
+}
+
+/// Play the alarm, retrying transient failures with exponential backoff.
+/// Fatal failures won't get better by retrying so they exit.
+async fn play_with_retry(config: &Config, req: &PlayRequest, player: &impl Play, stop: &Stop) {
+    let mut attempt = 0;
+    loop {
+        match player.play(config, req, stop).await {
+            Ok(()) => return,
+            Err(e @ PlayError::Fatal(_)) => {
+                eprintln!("Unable to play the alarm, {}", e);
+                exit(1);
+            }
+            Err(e) if attempt < config.max_retries => {
+                let delay = config.retry_delay(attempt);
+                eprintln!(
+                    "Unable to play the alarm: {}. Retrying in {}s",
+                    e,
+                    delay.as_secs()
+                );
+                tokio::select! {
+                    _ = sleep(delay) => {}
+                    _ = stop.requested() => return,
+                }
+                attempt += 1;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Unable to play the alarm: {}. Giving up after {} retries",
+                    e, attempt
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarm::{get_alarms, Selection};
+    use crate::days::DayNames;
+    use crate::spotify::Envelope;
+    use chrono::TimeZone;
+    use std::cell::RefCell;
+
+    /// Records what it was asked to play, failing the first `fail` times. With `hang`, playing
+    /// only finishes when it's stopped
+    #[derive(Default)]
+    struct MockPlayer {
+        played: RefCell<Vec<PlayRequest>>,
+        prepared: RefCell<Vec<PlayRequest>>,
+        started: RefCell<Vec<PlayRequest>>,
+        fail: RefCell<u32>,
+        chimes: RefCell<u32>,
+        hang: bool,
+        /// how many alarms were stopped and cleaned up after
+        stopped: RefCell<u32>,
+    }
+    impl Play for MockPlayer {
+        type Prepared = PlayRequest;
+        async fn prepare(&self, _: &Config, req: &PlayRequest) -> Result<PlayRequest, PlayError> {
+            self.prepared.borrow_mut().push(req.clone());
+            Ok(req.clone())
+        }
+        async fn start(
+            &self,
+            _: &Config,
+            prepared: PlayRequest,
+            _: &Stop,
+        ) -> Result<(), PlayError> {
+            self.started.borrow_mut().push(prepared);
+            Ok(())
+        }
+        async fn play(&self, _: &Config, req: &PlayRequest, stop: &Stop) -> Result<(), PlayError> {
+            self.played.borrow_mut().push(req.clone());
+            if *self.fail.borrow() > 0 {
+                *self.fail.borrow_mut() -= 1;
+                return Err(PlayError::Transient("offline".to_string()));
+            }
+            if self.hang {
+                stop.requested().await;
+                *self.stopped.borrow_mut() += 1;
+            }
+            Ok(())
+        }
+        async fn chime(&self, _: &Config, _: u8) -> Result<(), PlayError> {
+            *self.chimes.borrow_mut() += 1;
+            Ok(())
+        }
+    }
+
+    /// Tick, then let whatever went off play to the end
+    async fn tick(
+        scheduler: &mut Scheduler<MockPlayer>,
+        alarms: &mut [Alarm],
+        time: DateTime<Local>,
+        config: &Config,
+    ) {
+        scheduler.tick(alarms, time, config).await;
+        scheduler.wait().await;
+    }
+
+    #[tokio::test]
+    async fn fires_due_alarms_once() {
+        let config = Config::default();
+        let mut alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su playlists=spotify:show:2mTUnDkuKUkhiueKcVWoP0 news
+7:01 M,T,W,Th,F,S,Su later",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 30).unwrap();
+
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert_eq!(
+            *scheduler.player.played.borrow(),
+            vec![PlayRequest {
+                source: "spotify:show:2mTUnDkuKUkhiueKcVWoP0".to_string(),
+                resume: false,
+                stop_on_track_end: true,
+                pre_chime: false,
+                action: Action::Play,
+                selection: Selection::Random,
+                volume: 100,
+                envelope: Envelope::new(100, 0, 0, &[]),
+            }]
+        );
+        assert!(alarms[0].played);
+        assert!(!alarms[1].played);
+        assert_eq!(*scheduler.player.chimes.borrow(), 0);
+
+        let mut alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su pre_chime=true beep",
+            &DayNames::default(),
+        )
+        .unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert_eq!(*scheduler.player.chimes.borrow(), 1);
+        assert_eq!(scheduler.player.played.borrow().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn doesnt_refire_within_the_grace_period() {
+        let config = Config::default();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let alarms = get_alarms("7:00 M,T,W,Th,F,S,Su once", &DayNames::default()).unwrap();
+
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 58).unwrap();
+        let mut today = alarms.clone();
+        tick(&mut scheduler, &mut today, time, &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 1);
+
+        // reloaded without its played flag, e.g. the file was removed and put back
+        let mut reloaded = alarms.clone();
+        let time = time + chrono::Duration::seconds(1);
+        tick(&mut scheduler, &mut reloaded, time, &config).await;
+        assert!(reloaded[0].played);
+        assert_eq!(scheduler.player.played.borrow().len(), 1);
+
+        // unless it was reset
+        scheduler.forget(&alarms[0].key());
+        let mut reset = alarms.clone();
+        tick(&mut scheduler, &mut reset, time, &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 2);
+
+        // tomorrow it goes off as normal
+        let mut tomorrow = alarms.clone();
+        let time = Local.with_ymd_and_hms(2024, 1, 2, 7, 0, 0).unwrap();
+        tick(&mut scheduler, &mut tomorrow, time, &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 3);
+
+        // without a grace period a reloaded alarm goes off again
+        let config = crate::config::get_config("refire_grace_secs=0").unwrap();
+        let mut reloaded = alarms.clone();
+        tick(&mut scheduler, &mut reloaded, time, &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 4);
+    }
+    #[tokio::test]
+    async fn fast_forwards_through_a_day() {
+        // the time is passed in rather than read from the clock, so a whole day can be run
+        // through the same steps as main's loop
+        let config = Config::default();
+        let file = "7:00 M playlists=spotify:playlist:37i9dQZF1DX0UrRvztWcAU weekday
+6:30 M,T,W,Th,F,S,Su playlists=spotify:show:2mTUnDkuKUkhiueKcVWoP0 news
+9:00 T playlists=spotify:playlist:2aBMj4vGrpxavecIWQtcc4 tuesday
+23:59 M,T,W,Th,F,S,Su late";
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let mut alarms = vec![];
+        // a Monday, every 20 seconds
+        let start = Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        for step in 0..(24 * 60 * 3) {
+            let time = start + chrono::Duration::seconds(step * 20);
+            let loaded = get_alarms(file, &DayNames::default()).unwrap();
+            alarms = crate::alarm::get_valid_alarms(loaded, alarms, time);
+            tick(&mut scheduler, &mut alarms, time, &config).await;
+        }
+        let played = scheduler
+            .player
+            .played
+            .borrow()
+            .iter()
+            .map(|r| r.source.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            played,
+            vec![
+                "spotify:show:2mTUnDkuKUkhiueKcVWoP0",
+                "spotify:playlist:37i9dQZF1DX0UrRvztWcAU",
+                &config.default_playlist_uri,
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn forgets_fired_alarms_after_a_clock_jump() {
+        let second = std::time::Duration::from_secs(1);
+        assert!(!clock_jump(chrono::Duration::seconds(1), second, 120));
+        assert!(clock_jump(chrono::Duration::hours(1), second, 120));
+        assert!(clock_jump(chrono::Duration::hours(-1), second, 120));
+        assert!(!clock_jump(chrono::Duration::hours(-1), second, 0));
+
+        let config = Config::default();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let alarms = get_alarms("7:00 M,T,W,Th,F,S,Su once", &DayNames::default()).unwrap();
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+        tick(&mut scheduler, &mut alarms.clone(), time, &config).await;
+        scheduler.clock_jumped();
+        assert!(scheduler.fired.is_empty());
+
+        // it went off, so it doesn't again when the clock is put back over it
+        let mut played = alarms.clone();
+        played[0].played = true;
+        let time = time - chrono::Duration::minutes(1);
+        tick(&mut scheduler, &mut played, time, &config).await;
+        tick(
+            &mut scheduler,
+            &mut played,
+            time + chrono::Duration::minutes(1),
+            &config,
+        )
+        .await;
+        assert_eq!(scheduler.player.played.borrow().len(), 1);
+    }
+    #[tokio::test]
+    async fn keeps_a_gap_between_alarms() {
+        let alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su first
+7:01 M,T,W,Th,F,S,Su second
+7:03 M,T,W,Th,F,S,Su third",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let minute = |m| Local.with_ymd_and_hms(2024, 1, 1, 7, m, 0).unwrap();
+
+        let config = crate::config::get_config("min_gap_between_triggers_secs=150").unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let mut today = alarms.clone();
+        for m in 0..4 {
+            tick(&mut scheduler, &mut today, minute(m), &config).await;
+        }
+        assert!(today.iter().all(|a| a.played));
+        assert_eq!(scheduler.player.played.borrow().len(), 2);
+
+        let config =
+            crate::config::get_config("min_gap_between_triggers_secs=150\nmin_gap_policy=queue")
+                .unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let mut today = alarms.clone();
+        for m in 0..3 {
+            tick(&mut scheduler, &mut today, minute(m), &config).await;
+        }
+        assert_eq!(scheduler.player.played.borrow().len(), 1);
+        // the second one plays once the gap is over, and the third waits for it in turn
+        tick(&mut scheduler, &mut today, minute(3), &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 2);
+        assert_eq!(scheduler.queued.len(), 1);
+        tick(&mut scheduler, &mut today, minute(6), &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 3);
+
+        // a clock jump restarts the gap but doesn't drop what's waiting
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let mut today = alarms.clone();
+        for m in 0..2 {
+            tick(&mut scheduler, &mut today, minute(m), &config).await;
+        }
+        scheduler.clock_jumped();
+        assert_eq!(scheduler.queued.len(), 1);
+        tick(&mut scheduler, &mut today, minute(2), &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 2);
+    }
+    #[tokio::test]
+    async fn notify_alarms_dont_play() {
+        let config = crate::config::get_config("soft_start=true").unwrap();
+        let mut alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su action=notify pre_chime=true medication",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 6, 59, 30).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert!(alarms[0].played);
+        assert!(scheduler.player.prepared.borrow().is_empty());
+        assert!(scheduler.player.played.borrow().is_empty());
+        assert_eq!(*scheduler.player.chimes.borrow(), 0);
+    }
+    #[tokio::test]
+    async fn uses_group_settings() {
+        let config = crate::config::get_config(
+            "group.weekday.playlists=spotify:playlist:37i9dQZF1DX0UrRvztWcAU
+group.weekday.resume=true",
+        )
+        .unwrap();
+        let mut alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su group=weekday work",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert_eq!(
+            *scheduler.player.played.borrow(),
+            vec![PlayRequest {
+                source: "spotify:playlist:37i9dQZF1DX0UrRvztWcAU".to_string(),
+                resume: true,
+                stop_on_track_end: true,
+                pre_chime: false,
+                action: Action::Play,
+                selection: Selection::Random,
+                volume: 100,
+                envelope: Envelope::new(100, 0, 0, &[]),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let config = crate::config::get_config("retry_initial_secs=0\nmax_retries=2").unwrap();
+        let mut alarms = get_alarms("7:00 M,T,W,Th,F,S,Su flaky", &DayNames::default()).unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer {
+            fail: RefCell::new(5),
+            ..Default::default()
+        });
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        // the first try plus two retries, then it gives up
+        let played = scheduler.player.played.borrow();
+        assert_eq!(played.len(), 3);
+        assert_eq!(played[0].source, config.default_playlist_uri);
+    }
+    #[tokio::test]
+    async fn fires_alarms_while_another_retries() {
+        let config = crate::config::get_config("retry_initial_secs=60").unwrap();
+        let mut alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su flaky\n7:01 M,T,W,Th,F,S,Su next",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer {
+            fail: RefCell::new(1),
+            ..Default::default()
+        });
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+
+        // the first alarm fails and waits a minute to retry, which doesn't hold up the next
+        scheduler.tick(&mut alarms, time, &config).await;
+        scheduler.run_for(Duration::from_millis(10)).await;
+        let time = time + chrono::Duration::minutes(1);
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert!(alarms.iter().all(|a| a.played));
+        assert_eq!(scheduler.player.played.borrow().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fires_alarms_while_another_plays() {
+        let config = Config::default();
+        let mut alarms = get_alarms(
+            "7:00 M,T,W,Th,F,S,Su playlists=spotify:show:2mTUnDkuKUkhiueKcVWoP0 news
+7:01 M,T,W,Th,F,S,Su music
+7:01 M,T,W,Th,F,S,Su also",
+            &DayNames::default(),
+        )
+        .unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer {
+            hang: true,
+            ..Default::default()
+        });
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+
+        scheduler.tick(&mut alarms, time, &config).await;
+        scheduler.run_for(Duration::from_millis(10)).await;
+        assert!(scheduler.playing.is_some());
+        // the first one is still going when the second is due, which stops it and takes over.
+        // The third is due in the same minute as the second, so it doesn't cut that off
+        let time = time + chrono::Duration::minutes(1);
+        scheduler.tick(&mut alarms, time, &config).await;
+        assert_eq!(*scheduler.player.stopped.borrow(), 1);
+        scheduler.run_for(Duration::from_millis(10)).await;
+        let played = scheduler.player.played.borrow();
+        assert_eq!(played.len(), 2);
+        assert_eq!(played[1].source, config.default_playlist_uri);
+        assert_eq!(scheduler.playing.as_ref().unwrap().desc, "music");
+        assert!(alarms[2].played);
+    }
+
+    #[tokio::test]
+    async fn skips_disabled_days() {
+        let config = crate::config::get_config("disable=2024-01-01..2024-01-02").unwrap();
+        let mut alarms = get_alarms("7:00 M,T,W,Th,F,S,Su holiday", &DayNames::default()).unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+
+        let time = Local.with_ymd_and_hms(2024, 1, 2, 7, 0, 0).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert!(alarms[0].played);
+        assert!(scheduler.player.played.borrow().is_empty());
+
+        alarms[0].played = false;
+        let time = Local.with_ymd_and_hms(2024, 1, 3, 7, 0, 0).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert_eq!(scheduler.player.played.borrow().len(), 1);
+    }
+    #[tokio::test]
+    async fn soft_start_preloads_and_discards() {
+        let config = crate::config::get_config("soft_start=true").unwrap();
+        let mut alarms = get_alarms("7:00 M,T,W,Th,F,S,Su soft", &DayNames::default()).unwrap();
+        let mut scheduler = Scheduler::new(MockPlayer::default());
+
+        // too early to preload
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 6, 58, 30).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert!(scheduler.player.prepared.borrow().is_empty());
+
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 6, 59, 30).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert_eq!(scheduler.player.prepared.borrow().len(), 1);
+
+        // firing unpauses the preloaded track instead of playing from scratch
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert_eq!(scheduler.player.started.borrow().len(), 1);
+        assert!(scheduler.player.played.borrow().is_empty());
+
+        // an alarm edited after it was preloaded gets its track loaded again
+        let mut alarms = get_alarms("8:00 M,T,W,Th,F,S,Su soft", &DayNames::default()).unwrap();
+        let time = Local.with_ymd_and_hms(2024, 1, 1, 7, 59, 30).unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        let mut alarms = get_alarms(
+            "8:00 M,T,W,Th,F,S,Su resume=true soft",
+            &DayNames::default(),
+        )
+        .unwrap();
+        tick(&mut scheduler, &mut alarms, time, &config).await;
+        assert_eq!(scheduler.player.prepared.borrow().len(), 3);
+        assert!(scheduler.player.prepared.borrow()[2].resume);
+
+        // and one that's removed throws it away
+        tick(&mut scheduler, &mut [], time, &config).await;
+        assert!(scheduler.prewarmed.is_none());
+    }
+}
@@ -1,107 +1,580 @@
 use librespot::connect::spirc::Spirc;
 use librespot::core::cache::Cache;
 use librespot::discovery::DeviceType;
+#[cfg(target_os = "linux")]
+use librespot::playback::mixer::alsamixer::AlsaMixer;
 use librespot::playback::mixer::softmixer::SoftMixer;
 use librespot::playback::mixer::Mixer;
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::seq::SliceRandom;
-use tokio::join;
+use rand::Rng;
 
-use std::env;
+#[cfg(target_os = "linux")]
+use std::panic::{self, AssertUnwindSafe};
+use std::{cell::Cell, fmt, thread};
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
 
 use librespot::core::authentication::Credentials;
 use librespot::core::config::{ConnectConfig, SessionConfig};
 use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
-use librespot::metadata::{Metadata, Playlist, Track};
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use librespot::metadata::{Episode, Metadata, Playlist, Show, Track};
 use librespot::playback::audio_backend;
-use librespot::playback::config::{AudioFormat, PlayerConfig};
-use librespot::playback::mixer::{MixerConfig, NoOpVolume};
-use librespot::playback::player::{Player, PlayerEvent};
+use librespot::playback::config::{AudioFormat, Bitrate, PlayerConfig};
+use librespot::playback::mixer::MixerConfig;
+use librespot::playback::player::{Player, PlayerEvent, PlayerEventChannel};
 
-use crate::util::get_home_path;
+use crate::alarm::{source_kind, Action, Alarm, Selection, SourceKind, WeightedPlaylist};
+use crate::chime;
+use crate::config::Config;
+use crate::state;
+use crate::util::{self, get_home_path};
 
 const CREDS_PATH: &str = "creds";
 const VOL_PATH: &str = "vol";
 const AUDIO_PATH: &str = "audio";
-pub async fn play_alarm() {
-    let home_path = get_home_path().expect("Unable to get your home directory");
-    let cache = Cache::new(
-        Some(format!("{}/{}", home_path.to_str().unwrap(), CREDS_PATH)),
-        Some(format!("{}/{}", home_path.to_str().unwrap(), VOL_PATH)),
-        Some(format!("{}/{}", home_path.to_str().unwrap(), AUDIO_PATH)),
-        None,
-    )
-    .unwrap();
+/// how long past the end of its track an alarm can run before the watchdog stops it
+const WATCHDOG_MARGIN_SECS: u64 = 60;
+/// volume in percent `--selftest` plays at
+const SELFTEST_VOLUME: u8 = 10;
+/// how long `--selftest` plays for once the track has started
+const SELFTEST_SECS: u64 = 3;
+/// how long `--selftest` waits for the track to start
+const SELFTEST_TIMEOUT_SECS: u64 = 30;
 
-    let credentials = match cache.credentials() {
-        Some(c) => {
-            println!("using saved credentials");
-            c
+#[derive(Debug)]
+pub enum PlayError {
+    /// retrying won't help, e.g. there's no audio backend or no credentials
+    Fatal(String),
+    /// this alarm failed but the next one might work, e.g. the network is down
+    Transient(String),
+}
+impl fmt::Display for PlayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayError::Fatal(e) => write!(f, "fatal: {}", e),
+            PlayError::Transient(e) => write!(f, "{}", e),
         }
-        None => {
-            let args: Vec<_> = env::args().collect();
-            if args.len() != 3 {
-                eprintln!("Usage: {} USERNAME PASSWORD", args[0]);
-                return;
+    }
+}
+
+/// Everything that decides what one alarm plays
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayRequest {
+    /// playlist, show or episode to play
+    pub source: String,
+    /// continue the playlist in order instead of picking a random track
+    pub resume: bool,
+    /// end the alarm after one track, rather than playing on through the playlist
+    pub stop_on_track_end: bool,
+    /// beep before the music starts
+    pub pre_chime: bool,
+    pub action: Action,
+    pub selection: Selection,
+    /// in percent, for the chime
+    pub volume: u8,
+    /// how the music's volume changes over the alarm
+    pub envelope: Envelope,
+}
+impl PlayRequest {
+    pub fn for_alarm(config: &Config, alarm: &Alarm, rng: &mut impl Rng) -> PlayRequest {
+        let settings = alarm.settings_in(config);
+        let source = match settings.playlists.as_slice() {
+            [] => config.default_playlist_uri.as_str(),
+            playlists => pick_playlist(playlists, rng),
+        };
+        let volume = settings.volume.unwrap_or(config.volume);
+        PlayRequest {
+            source: source.to_string(),
+            resume: settings.resume.unwrap_or(false),
+            stop_on_track_end: settings.stop_on_track_end.unwrap_or(true),
+            pre_chime: settings.pre_chime.unwrap_or(false),
+            action: settings.action.unwrap_or(Action::Play),
+            selection: settings.selection.unwrap_or(Selection::Random),
+            volume,
+            envelope: Envelope::new(
+                volume,
+                settings.fade_in_secs.unwrap_or(config.fade_in_secs),
+                settings.fade_min_volume.unwrap_or(config.fade_min_volume),
+                settings
+                    .volume_profile
+                    .as_deref()
+                    .unwrap_or(&config.volume_profile),
+            ),
+        }
+    }
+}
+
+/// Asks a playing alarm to stop early, e.g. because another one is going off. The alarm
+/// still cleans up after itself, like putting the system volume back
+#[derive(Clone)]
+pub struct Stop(watch::Receiver<bool>);
+impl Stop {
+    /// A stop signal, and the sender that asks for it
+    pub fn channel() -> (watch::Sender<bool>, Stop) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Stop(rx))
+    }
+
+    /// Wait until the alarm is asked to stop
+    pub async fn requested(&self) {
+        let mut rx = self.0.clone();
+        // nothing can ask once the sender's gone
+        if rx.wait_for(|stop| *stop).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Plays alarms, so the scheduler can be tested without a real Spotify session
+pub trait Play {
+    type Prepared;
+    /// Connect and load the alarm's track paused, so `start` only has to unpause it
+    async fn prepare(
+        &self,
+        config: &Config,
+        req: &PlayRequest,
+    ) -> Result<Self::Prepared, PlayError>;
+    /// Play a prepared alarm until it ends or `stop` is requested
+    async fn start(
+        &self,
+        config: &Config,
+        prepared: Self::Prepared,
+        stop: &Stop,
+    ) -> Result<(), PlayError>;
+    async fn play(&self, config: &Config, req: &PlayRequest, stop: &Stop) -> Result<(), PlayError>;
+    /// Beep through the audio backend at `volume` percent, without Spotify
+    async fn chime(&self, config: &Config, volume: u8) -> Result<(), PlayError>;
+}
+
+pub struct Spotify;
+impl Play for Spotify {
+    type Prepared = Prepared;
+    async fn prepare(&self, config: &Config, req: &PlayRequest) -> Result<Prepared, PlayError> {
+        prepare(config, req, true).await
+    }
+    async fn start(
+        &self,
+        config: &Config,
+        prepared: Prepared,
+        stop: &Stop,
+    ) -> Result<(), PlayError> {
+        start(config, prepared, stop).await
+    }
+    async fn play(&self, config: &Config, req: &PlayRequest, stop: &Stop) -> Result<(), PlayError> {
+        play(config, req, stop).await
+    }
+    async fn chime(&self, config: &Config, volume: u8) -> Result<(), PlayError> {
+        chime::chime(config, volume).await
+    }
+}
+
+/// A connected session with the alarm's track loaded, ready to `start`.
+/// Dropping it discards the loaded track.
+pub struct Prepared {
+    session: Session,
+    player: Player,
+    player_event: PlayerEventChannel,
+    /// the track is loaded paused and `start` has to unpause it
+    paused: bool,
+    /// force the track to stop after this long even if it hasn't ended (`max_duration_secs`)
+    max_duration: Option<Duration>,
+    mixer: SoftMixer,
+    req: PlayRequest,
+    /// the track that's loaded, and where it starts
+    position: Position,
+    /// what to remember about the pick once the track starts playing
+    pick: Option<Pick>,
+}
+
+/// A track picked from a playlist. It's only remembered for `avoid_repeats` and a resuming
+/// alarm's cursor once it starts playing, so a prewarmed track that's thrown away doesn't count.
+struct Pick {
+    playlist: String,
+    track: String,
+    /// how many recent tracks to keep (0 = don't keep any)
+    keep_recent: usize,
+    /// the track's index in the playlist, for a resuming alarm
+    cursor: Option<usize>,
+}
+impl Pick {
+    fn record(&self) {
+        if self.keep_recent > 0 {
+            if let Err(e) = state::add_recent_track(&self.playlist, &self.track, self.keep_recent) {
+                eprintln!("{}", e);
+            }
+        }
+        if let Some(index) = self.cursor {
+            if let Err(e) = state::set_cursor(&self.playlist, index) {
+                eprintln!("{}", e);
             }
-            let cred = Credentials::with_password(&args[1], &args[2]);
+        }
+    }
+}
 
-            cache.save_credentials(&cred);
-            cred
+/// How far into which track playback got
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    track: SpotifyId,
+    duration_ms: i32,
+    position_ms: u32,
+}
+
+/// Why a track stopped playing
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ended {
+    /// it played to the end
+    Finished,
+    /// someone paused it from a Spotify app, or the watchdog stopped it
+    Stopped,
+    /// it stopped on its own partway through, e.g. the stream failed or Connect dropped
+    Interrupted(Position),
+}
+
+/// The volume in percent over the course of an alarm, as `(seconds in, volume)` points in
+/// order. It's interpolated between the points, and holds at the first and last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    points: Vec<(u32, u8)>,
+}
+impl Envelope {
+    /// A volume `profile`, or if that's empty a fade up to `volume` over `fade_in_secs`
+    pub fn new(
+        volume: u8,
+        fade_in_secs: u32,
+        fade_min_volume: u8,
+        profile: &[(u32, u8)],
+    ) -> Envelope {
+        if !profile.is_empty() {
+            return Envelope {
+                points: profile.to_vec(),
+            };
+        }
+        let mut points = vec![];
+        if fade_in_secs > 0 {
+            points.push((0, fade_min_volume));
+        }
+        points.push((fade_in_secs, volume));
+        Envelope { points }
+    }
+
+    /// The volume in percent `elapsed` into the alarm
+    fn volume_at(&self, elapsed: Duration) -> u8 {
+        let secs = elapsed.as_secs_f64();
+        let mut last = self.points[0];
+        for &(at, volume) in &self.points {
+            if secs < at as f64 {
+                if at == last.0 {
+                    return volume;
+                }
+                let done = (secs - last.0 as f64) / (at - last.0) as f64;
+                return (last.1 as f64 + (volume as f64 - last.1 as f64) * done).round() as u8;
+            }
+            last = (at, volume);
         }
+        last.1
+    }
+
+    /// How long after the start the volume stops changing
+    fn len(&self) -> Duration {
+        Duration::from_secs(self.points.last().map_or(0, |p| p.0) as u64)
+    }
+}
+
+/// librespot volumes go from 0 to u16::MAX
+fn mixer_volume(percent: u8) -> u16 {
+    (percent as u32 * u16::MAX as u32 / 100) as u16
+}
+
+pub async fn play(config: &Config, req: &PlayRequest, stop: &Stop) -> Result<(), PlayError> {
+    let prepared = tokio::select! {
+        prepared = prepare(config, req, false) => prepared?,
+        _ = stop.requested() => return Ok(()),
     };
+    start(config, prepared, stop).await
+}
 
+/// Connect to Spotify and load the alarm's track, playing it straight away unless `paused`
+pub async fn prepare(
+    config: &Config,
+    req: &PlayRequest,
+    paused: bool,
+) -> Result<Prepared, PlayError> {
     let mut rng = rand::thread_rng();
-    let session_config = SessionConfig::default();
-    let player_config = PlayerConfig::default();
-    let audio_format = AudioFormat::default();
-    let backend = audio_backend::find(None).unwrap();
-    let connect_config = ConnectConfig {
-        name: "PiAlarm".to_string(),
-        device_type: DeviceType::default(),
-        initial_volume: Some(100),
-        has_volume_ctrl: false,
-        autoplay: false,
-    };
+    let backend = find_backend(config.audio_backend.clone())?;
+    let session = connect().await?;
 
-    println!("Connecting ..");
-    let (session, _) = Session::connect(session_config, credentials, None, false)
-        .await
-        .unwrap();
+    // pick a random track from the alarm playlist (or the podcast episode to play)
+    let (track, duration_ms, pick) = resolve_source(&session, req, config, &mut rng).await?;
 
+    // https://open.spotify.com/track/5PbMSJZcNA3p2LZv7C56cm?si=d83209b036a64047
+    // let track = SpotifyId::from_base62("5PbMSJZcNA3p2LZv7C56cm").unwrap(); // 4 seconds
+    //https://open.spotify.com/track/6UCFZ9ZOFRxK8oak7MdPZu?si=e14c5c002f064429
+    // let track = SpotifyId::from_base62("6UCFZ9ZOFRxK8oak7MdPZu").unwrap(); // 20 something seconds
+    // let print_track = Track::get(&session, track).await.unwrap();
+    // println!(">>{}", print_track.name);
+
+    let position = Position {
+        track,
+        duration_ms,
+        position_ms: 0,
+    };
+    load(config, req, backend, session, position, pick, paused).await
+}
+
+/// Load a track on a new player, starting `position_ms` into it
+async fn load(
+    config: &Config,
+    req: &PlayRequest,
+    backend: audio_backend::SinkBuilder,
+    session: Session,
+    position: Position,
+    pick: Option<Pick>,
+    paused: bool,
+) -> Result<Prepared, PlayError> {
+    let player_config = PlayerConfig {
+        bitrate: match config.bitrate {
+            96 => Bitrate::Bitrate96,
+            320 => Bitrate::Bitrate320,
+            _ => Bitrate::Bitrate160,
+        },
+        normalisation: config.normalisation,
+        ..PlayerConfig::default()
+    };
+    let audio_format = AudioFormat::default();
+    let mixer = SoftMixer::open(MixerConfig::default());
     let (mut player, mut player_event) = Player::new(
         player_config,
         session.clone(),
-        Box::new(NoOpVolume),
+        mixer.get_soft_volume(),
         move || backend(None, audio_format),
     );
 
-    // pick a random track from the alarm playlist
-    let plist = "spotify:playlist:2aBMj4vGrpxavecIWQtcc4"; // alarm
-    let plist_uri = SpotifyId::from_uri(plist).unwrap();
+    // play the track. To pre-buffer it's loaded paused and started once it's had time to
+    // download some, like a preloaded alarm
+    let prebuffer = !paused && config.prebuffer_secs > 0;
+    let wait = paused || prebuffer;
+    player.load(position.track, !wait, position.position_ms);
+    if wait {
+        // wait for it to finish loading so starting it later is instant
+        loop {
+            match player_event.recv().await {
+                Some(PlayerEvent::Paused { .. }) => break,
+                Some(PlayerEvent::Unavailable { .. }) | None => {
+                    return Err(PlayError::Transient(
+                        "unable to load the alarm track".to_string(),
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    if prebuffer {
+        sleep(Duration::from_secs(config.prebuffer_secs as u64)).await;
+    }
+    let max_duration = (config.max_duration_secs > 0)
+        .then(|| Duration::from_secs(config.max_duration_secs as u64));
+    Ok(Prepared {
+        session,
+        player,
+        player_event,
+        paused: wait,
+        max_duration,
+        mixer,
+        req: req.clone(),
+        position,
+        pick,
+    })
+}
+
+/// Play a prepared alarm until its track ends, or on through the playlist if the alarm
+/// doesn't `stop_on_track_end`
+pub async fn start(config: &Config, prepared: Prepared, stop: &Stop) -> Result<(), PlayError> {
+    let previous = set_system_volume(config).await;
+    // stopping drops the player, which ends playback, and the volume is still put back
+    let played = tokio::select! {
+        played = play_tracks(config, prepared) => played,
+        _ = stop.requested() => {
+            println!("Stopping the alarm");
+            Ok(())
+        }
+    };
+    if let (Some(previous), true) = (previous, config.restore_system_volume) {
+        restore_system_volume(config, previous).await;
+    }
+    played
+}
 
-    let plist = Playlist::get(&session, plist_uri).await.unwrap();
-    let track = *plist.tracks.choose(&mut rng).unwrap();
-    let print_track = Track::get(&session, track).await.unwrap();
-    println!("{}", print_track.name);
+async fn play_tracks(config: &Config, prepared: Prepared) -> Result<(), PlayError> {
+    let req = prepared.req.clone();
+    // a show or episode would only play the same episode again
+    let keep_playing =
+        !req.stop_on_track_end && source_kind(&req.source) == Some(SourceKind::Playlist);
+    // the volume follows the envelope from the start of the alarm, across tracks
+    let envelope = req.envelope.clone();
+    let started = Instant::now();
+    // a single track or episode stops at its end, however long it is
+    let deadline = (keep_playing && config.max_alarm_secs > 0)
+        .then(|| started + Duration::from_secs(config.max_alarm_secs as u64));
+    // later tracks are played on the same session
+    let mut session = prepared.session.clone();
+    let mut retries = 0;
+    let mut ended = play_track(prepared, &envelope, started, deadline).await?;
+    loop {
+        let next = match ended {
+            Ended::Finished if keep_playing && deadline.is_none_or(|d| Instant::now() < d) => {
+                match next_track(config, &req, &mut session).await {
+                    Ok(next) => next,
+                    Err(e) => {
+                        eprintln!("Unable to play the next track, ending the alarm: {}", e);
+                        return Ok(());
+                    }
+                }
+            }
+            Ended::Interrupted(position) if retries < config.playback_retries => {
+                retries += 1;
+                eprintln!(
+                    "The alarm stopped unexpectedly, picking it up again {}s in",
+                    position.position_ms / 1000
+                );
+                let backend = find_backend(config.audio_backend.clone())?;
+                session = connect().await?;
+                load(
+                    config,
+                    &req,
+                    backend,
+                    session.clone(),
+                    position,
+                    None,
+                    false,
+                )
+                .await?
+            }
+            Ended::Interrupted(_) => {
+                eprintln!(
+                    "The alarm stopped unexpectedly, giving up after {} retries",
+                    retries
+                );
+                return Ok(());
+            }
+            Ended::Finished | Ended::Stopped => return Ok(()),
+        };
+        ended = play_track(next, &envelope, started, deadline).await?;
+    }
+}
 
-    // https://open.spotify.com/track/5PbMSJZcNA3p2LZv7C56cm?si=d83209b036a64047
-    // let track = SpotifyId::from_base62("5PbMSJZcNA3p2LZv7C56cm").unwrap(); // 4 seconds
-    //https://open.spotify.com/track/6UCFZ9ZOFRxK8oak7MdPZu?si=e14c5c002f064429
-    // let track = SpotifyId::from_base62("6UCFZ9ZOFRxK8oak7MdPZu").unwrap(); // 20 something seconds
-    // let print_track = Track::get(&session, track).await.unwrap();
-    // println!(">>{}", print_track.name);
+/// Pick and load the alarm's next track on the session it's been playing on, reconnecting
+/// only if that's gone
+async fn next_track(
+    config: &Config,
+    req: &PlayRequest,
+    session: &mut Session,
+) -> Result<Prepared, PlayError> {
+    let backend = find_backend(config.audio_backend.clone())?;
+    if session.is_invalid() {
+        *session = connect().await?;
+    }
+    let (track, duration_ms, pick) =
+        resolve_source(session, req, config, &mut rand::thread_rng()).await?;
+    let position = Position {
+        track,
+        duration_ms,
+        position_ms: 0,
+    };
+    load(config, req, backend, session.clone(), position, pick, false).await
+}
 
-    // play the track
-    player.load(track, true, 0);
+/// Play one prepared track until it stops, at the envelope's volume for an alarm that
+/// started at `started`, stopping it at the alarm's `deadline`
+async fn play_track(
+    prepared: Prepared,
+    envelope: &Envelope,
+    started: Instant,
+    deadline: Option<Instant>,
+) -> Result<Ended, PlayError> {
+    let Prepared {
+        session,
+        player,
+        mut player_event,
+        paused,
+        max_duration,
+        mixer,
+        req: _,
+        position,
+        mut pick,
+    } = prepared;
+    let volume = mixer_volume(envelope.volume_at(started.elapsed()));
+    mixer.set_volume(volume);
+    if paused {
+        player.play();
+    }
+    let connect_config = ConnectConfig {
+        name: "PiAlarm".to_string(),
+        device_type: DeviceType::default(),
+        initial_volume: Some(volume),
+        has_volume_ctrl: false,
+        autoplay: false,
+    };
     let (spirc, spirc_task) = Spirc::new(
         connect_config,
         session.clone(),
         player,
-        Box::new(SoftMixer::open(MixerConfig::default())),
+        Box::new(mixer.clone()),
     );
+    let follow = started.elapsed() < envelope.len();
+    let _fade = follow.then(|| {
+        AbortOnDrop(tokio::spawn(follow_envelope(
+            mixer,
+            envelope.clone(),
+            started,
+        )))
+    });
 
-    join!(spirc_task, async {
+    // the spirc task normally ends because we shut it down at the end of the track. If it ends
+    // on its own the Connect session dropped mid-alarm
+    let shutting_down = Cell::new(false);
+    let ended = Cell::new(Ended::Stopped);
+    // the position at the last Playing event, and when that was, to know where to pick up
+    // from if playback is interrupted
+    let playing_from = Cell::new((position.position_ms, None));
+    // set when the track couldn't be loaded or played, so a Stopped after it is a failure
+    // rather than another device taking over
+    let failed = Cell::new(false);
+    // a track can't play for longer than it has left from where it's playing, so a while past
+    // that the alarm has hung. It's worked out again whenever playback (re)starts, e.g. after
+    // a seek, so it follows the position rather than when the track was loaded
+    let margin = Duration::from_secs(WATCHDOG_MARGIN_SECS);
+    let limit = max_duration.map(|d| Instant::now() + d + margin);
+    let watchdog_from = |position_ms: u32| {
+        let left_ms = (position.duration_ms.max(0) as u64).saturating_sub(position_ms as u64);
+        let at = Instant::now() + Duration::from_millis(left_ms) + margin;
+        limit.map_or(at, |l| l.min(at))
+    };
+    let watchdog = Cell::new(watchdog_from(position.position_ms));
+    let stop_at = || {
+        let at = watchdog.get();
+        deadline.map_or(at, |d| d.min(at))
+    };
+    // wakes up again if the watchdog moved while it slept
+    let stopping = async {
+        loop {
+            let at = stop_at();
+            sleep_until(at).await;
+            if stop_at() <= Instant::now() {
+                break;
+            }
+        }
+    };
+    let played_to = || {
+        let (position_ms, since) = playing_from.get();
+        let elapsed = since.map_or(0, |t: Instant| t.elapsed().as_millis() as u32);
+        Position {
+            position_ms: position_ms + elapsed,
+            ..position
+        }
+    };
+    let events = async {
         println!("Playing...");
         spirc.play();
 
@@ -109,12 +582,627 @@ pub async fn play_alarm() {
             match event {
                 // end the alarm if the track stops
                 // the app will start looking for the next alarm
-                PlayerEvent::EndOfTrack { .. }
-                | PlayerEvent::Paused { .. }
-                | PlayerEvent::Stopped { .. } => spirc.shutdown(),
+                PlayerEvent::EndOfTrack { .. } => {
+                    ended.set(Ended::Finished);
+                    shutting_down.set(true);
+                    spirc.shutdown()
+                }
+                PlayerEvent::Playing { position_ms, .. } => {
+                    if let Some(pick) = pick.take() {
+                        pick.record();
+                    }
+                    playing_from.set((position_ms, Some(Instant::now())));
+                    watchdog.set(watchdog_from(position_ms));
+                }
+                // pausing it from the Spotify app is how to turn the alarm off
+                PlayerEvent::Paused { .. } => {
+                    shutting_down.set(true);
+                    spirc.shutdown()
+                }
+                PlayerEvent::Unavailable { .. } => failed.set(true),
+                // we didn't stop it. Either something went wrong, or playback moved to
+                // another Connect device, which is how it's stopped from the Spotify app too
+                PlayerEvent::Stopped { .. } if !shutting_down.get() => {
+                    if failed.get() || session.is_invalid() {
+                        ended.set(Ended::Interrupted(played_to()));
+                    } else {
+                        println!("Playback moved to another device, stopping the alarm");
+                    }
+                    shutting_down.set(true);
+                    spirc.shutdown()
+                }
                 _ => {}
             }
         }
-        println!("Done...");
-    });
+    };
+    tokio::select! {
+        _ = spirc_task => {
+            if !shutting_down.get() {
+                eprintln!("The Spotify Connect session ended before the alarm finished");
+                ended.set(Ended::Interrupted(played_to()));
+            }
+        }
+        _ = events => {}
+        _ = stopping => {
+            if deadline.is_some_and(|d| d <= Instant::now()) {
+                println!("The alarm has played for max_alarm_secs, stopping it");
+            } else {
+                // e.g. the stream stalled and the track will never end
+                eprintln!(
+                    "The alarm was still playing {}s after it should have ended, forcing it to stop",
+                    WATCHDOG_MARGIN_SECS
+                );
+            }
+            spirc.shutdown();
+        }
+    }
+    println!("Done...");
+    Ok(ended.get())
+}
+
+#[cfg(target_os = "linux")]
+fn system_mixer_config(config: &Config) -> MixerConfig {
+    MixerConfig {
+        control: config.system_mixer_control.clone(),
+        ..MixerConfig::default()
+    }
+}
+
+/// Set the output device's own volume to `system_volume`, returning what it was before if
+/// that could be read. librespot's ALSA mixer panics instead of returning errors, so it's
+/// used on a blocking thread where a panic only fails the task.
+#[cfg(target_os = "linux")]
+async fn set_system_volume(config: &Config) -> Option<u16> {
+    let percent = config.system_volume?;
+    let mixer_config = system_mixer_config(config);
+    let set = tokio::task::spawn_blocking(move || {
+        let mixer = AlsaMixer::open(mixer_config);
+        let previous = panic::catch_unwind(AssertUnwindSafe(|| mixer.volume())).ok();
+        mixer.set_volume(mixer_volume(percent));
+        previous
+    })
+    .await;
+    match set {
+        Ok(previous) => {
+            if previous.is_none() && config.restore_system_volume {
+                eprintln!("Unable to read the system volume, it won't be restored");
+            }
+            previous
+        }
+        Err(_) => {
+            eprintln!("Unable to set the system volume, playing at the current one");
+            None
+        }
+    }
+}
+
+/// The ALSA mixer is only built on Linux, and the config refuses `system_volume` elsewhere
+#[cfg(not(target_os = "linux"))]
+async fn set_system_volume(_: &Config) -> Option<u16> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+async fn restore_system_volume(config: &Config, volume: u16) {
+    let mixer_config = system_mixer_config(config);
+    let restored =
+        tokio::task::spawn_blocking(move || AlsaMixer::open(mixer_config).set_volume(volume)).await;
+    if restored.is_err() {
+        eprintln!("Unable to restore the system volume");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn restore_system_volume(_: &Config, _: u16) {}
+
+/// A spawned task that's cancelled when this is dropped, however the alarm ends
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn follow_envelope(mixer: SoftMixer, envelope: Envelope, started: Instant) {
+    loop {
+        let elapsed = started.elapsed();
+        mixer.set_volume(mixer_volume(envelope.volume_at(elapsed)));
+        if elapsed >= envelope.len() {
+            return;
+        }
+        sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Log in to Spotify with the saved credentials, or the ones on the command line
+async fn connect() -> Result<Session, PlayError> {
+    let home_path = get_home_path().expect("Unable to get your home directory");
+    let cache = Cache::new(
+        Some(format!("{}/{}", home_path.to_str().unwrap(), CREDS_PATH)),
+        Some(format!("{}/{}", home_path.to_str().unwrap(), VOL_PATH)),
+        Some(format!("{}/{}", home_path.to_str().unwrap(), AUDIO_PATH)),
+        None,
+    )
+    .unwrap();
+
+    let credentials = match cache.credentials() {
+        Some(c) => {
+            println!("using saved credentials");
+            c
+        }
+        None => {
+            let Some((username, password)) = util::login_args() else {
+                return Err(PlayError::Fatal(format!(
+                    "no saved credentials. Usage: {} USERNAME PASSWORD",
+                    util::positional_args()[0]
+                )));
+            };
+            let cred = Credentials::with_password(&username, &password);
+
+            cache.save_credentials(&cred);
+            cred
+        }
+    };
+
+    println!("Connecting ..");
+    let (session, _) = Session::connect(SessionConfig::default(), credentials, None, false)
+        .await
+        .map_err(|e| PlayError::Transient(format!("unable to connect to Spotify: {}", e)))?;
+    Ok(session)
+}
+
+/// Play a few seconds of the default playlist quietly and check the player says it started,
+/// to test logging in, fetching the playlist and the audio backend together
+pub async fn selftest(config: &Config) -> Result<(), PlayError> {
+    // don't count the test track as played
+    let config = Config {
+        avoid_repeats: 0,
+        ..config.clone()
+    };
+    let req = PlayRequest {
+        source: config.default_playlist_uri.clone(),
+        resume: false,
+        stop_on_track_end: true,
+        pre_chime: false,
+        action: Action::Play,
+        selection: Selection::Random,
+        volume: SELFTEST_VOLUME,
+        envelope: Envelope::new(SELFTEST_VOLUME, 0, 0, &[]),
+    };
+    let mut prepared = prepare(&config, &req, false).await?;
+    prepared.mixer.set_volume(mixer_volume(SELFTEST_VOLUME));
+    // prebuffering leaves the track paused
+    let player = &prepared.player;
+    wait_until_playing(
+        &mut prepared.player_event,
+        prepared.paused.then_some(|| player.play()),
+        Duration::from_secs(SELFTEST_TIMEOUT_SECS),
+    )
+    .await?;
+    sleep(Duration::from_secs(SELFTEST_SECS)).await;
+    prepared.player.stop();
+    Ok(())
+}
+
+/// Wait for a loaded track to start playing, first calling `unpause` if it was loaded paused
+async fn wait_until_playing(
+    player_event: &mut PlayerEventChannel,
+    unpause: Option<impl FnOnce()>,
+    timeout: Duration,
+) -> Result<(), PlayError> {
+    if let Some(unpause) = unpause {
+        unpause();
+    }
+    let playing = tokio::time::timeout(timeout, async {
+        loop {
+            match player_event.recv().await {
+                Some(PlayerEvent::Playing { .. }) => return true,
+                Some(PlayerEvent::Unavailable { .. })
+                | Some(PlayerEvent::Stopped { .. })
+                | None => return false,
+                Some(_) => {}
+            }
+        }
+    })
+    .await;
+    match playing {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(PlayError::Transient(
+            "the test track didn't play".to_string(),
+        )),
+        Err(_) => Err(PlayError::Transient(format!(
+            "the test track hadn't started after {}s",
+            timeout.as_secs()
+        ))),
+    }
+}
+
+/// Connect and fetch the default playlist, show or episode without playing anything,
+/// returning how long the fetch took
+pub async fn ping(config: &Config) -> Result<Duration, PlayError> {
+    let uri = config.default_playlist_uri.as_str();
+    let id = SpotifyId::from_uri(uri)
+        .map_err(|_| PlayError::Fatal(format!("invalid default_playlist_uri {}", uri)))?;
+    let session = connect().await?;
+    let started = tokio::time::Instant::now();
+    let fetched = match source_kind(uri) {
+        Some(SourceKind::Episode) => Episode::get(&session, id).await.map(|_| ()),
+        Some(SourceKind::Show) => Show::get(&session, id).await.map(|_| ()),
+        _ => Playlist::get(&session, id).await.map(|_| ()),
+    };
+    fetched
+        .map_err(|_| PlayError::Transient("unable to fetch the default playlist".to_string()))?;
+    Ok(started.elapsed())
+}
+
+/// Check that the audio backend exists and its device opens, without playing anything
+pub fn check_audio(config: &Config) -> Result<(), PlayError> {
+    let backend = find_backend(config.audio_backend.clone())?;
+    let audio_format = AudioFormat::default();
+    // sinks panic when their device can't be opened, so open it on its own thread
+    thread::spawn(move || {
+        let mut sink = backend(None, audio_format);
+        sink.start().and_then(|_| sink.stop())
+    })
+    .join()
+    .map_err(|_| PlayError::Fatal("unable to open the audio device".to_string()))?
+    .map_err(|e| PlayError::Fatal(format!("unable to open the audio device: {}", e)))
+}
+
+/// Look up the audio backend by name (or the default one), listing the available backends
+/// if it can't be found.
+pub fn find_backend(name: Option<String>) -> Result<audio_backend::SinkBuilder, PlayError> {
+    audio_backend::find(name.clone()).ok_or_else(|| {
+        let available = audio_backend::BACKENDS
+            .iter()
+            .map(|b| b.0)
+            .collect::<Vec<&str>>()
+            .join(", ");
+        PlayError::Fatal(format!(
+            "no audio backend {}found. Available backends: {}",
+            name.map(|n| format!("\"{}\" ", n)).unwrap_or_default(),
+            available
+        ))
+    })
+}
+
+/// Turn an alarm source into the track or episode to play, its length in milliseconds, and
+/// for a playlist what to remember once it plays
+async fn resolve_source(
+    session: &Session,
+    req: &PlayRequest,
+    config: &Config,
+    rng: &mut impl Rng,
+) -> Result<(SpotifyId, i32, Option<Pick>), PlayError> {
+    let (uri, resume) = (req.source.as_str(), req.resume);
+    let id = SpotifyId::from_uri(uri).unwrap();
+    match source_kind(uri) {
+        Some(SourceKind::Episode) => {
+            let episode = Episode::get(session, id).await.map_err(|_| {
+                PlayError::Transient("unable to fetch the alarm episode".to_string())
+            })?;
+            println!("{}", episode.name);
+            Ok((id, episode.duration, None))
+        }
+        Some(SourceKind::Show) => {
+            // Spotify lists a show's episodes newest first
+            let show = Show::get(session, id)
+                .await
+                .map_err(|_| PlayError::Transient("unable to fetch the alarm show".to_string()))?;
+            let mut episode = *show
+                .episodes
+                .first()
+                .ok_or_else(|| PlayError::Transient(format!("{} has no episodes", show.name)))?;
+            episode.audio_type = SpotifyAudioType::Podcast;
+            println!("{}", show.name);
+            // the watchdog needs the episode's length, so don't guess at it
+            let duration = Episode::get(session, episode)
+                .await
+                .map_err(|_| {
+                    PlayError::Transient("unable to fetch the show's latest episode".to_string())
+                })?
+                .duration;
+            Ok((episode, duration, None))
+        }
+        _ => {
+            let plist = Playlist::get(session, id).await.map_err(|_| {
+                PlayError::Transient("unable to fetch the alarm playlist".to_string())
+            })?;
+            // Spotify can send back only the start of a very large playlist, and librespot 0.4
+            // has no way to page through the rest, so tracks past that are never picked. It
+            // warns (through the logger in util) when that happens, and the README says to
+            // split such a playlist. Tracks are only resolved one at a time in pick_track, so a
+            // huge playlist costs a metadata request per candidate, not per track.
+            println!(
+                "Picking from {} tracks in {}",
+                plist.tracks.len(),
+                plist.name
+            );
+            let cursor = if resume {
+                Some(state::get_cursor(uri))
+            } else {
+                None
+            };
+            let mut tracks = track_order(&plist.tracks, cursor, req.selection, rng);
+            if !resume && config.avoid_repeats > 0 {
+                tracks = avoid_recent(tracks, &state::get_recent_tracks(uri));
+            }
+            let (track, print_track) = pick_track(session, &tracks, config.min_track_secs)
+                .await
+                .ok_or_else(|| {
+                    PlayError::Transient("the alarm playlist has no playable tracks".to_string())
+                })?;
+            println!("{}", print_track.name);
+            let pick = Pick {
+                playlist: uri.to_string(),
+                track: track.to_base62().unwrap_or_default(),
+                keep_recent: config.avoid_repeats as usize,
+                cursor: resume.then(|| plist.tracks.iter().position(|t| *t == track).unwrap_or(0)),
+            };
+            Ok((track, print_track.duration, Some(pick)))
+        }
+    }
+}
+
+/// Pick one of the playlists, weighted by each playlist's weight
+fn pick_playlist<'a>(playlists: &'a [WeightedPlaylist], rng: &mut impl Rng) -> &'a str {
+    match WeightedIndex::new(playlists.iter().map(|p| p.weight)) {
+        Ok(dist) => &playlists[dist.sample(rng)].uri,
+        Err(_) => &playlists[0].uri,
+    }
+}
+
+/// The order to try the playlist's tracks in. Shuffled, unless `cursor` is set (a resuming
+/// alarm), then in order starting after the last track played, or the first if there isn't one.
+fn track_order(
+    tracks: &[SpotifyId],
+    cursor: Option<Option<usize>>,
+    selection: Selection,
+    rng: &mut impl Rng,
+) -> Vec<SpotifyId> {
+    let mut tracks = tracks.to_vec();
+    match (cursor, selection) {
+        (Some(_), _) if tracks.is_empty() => {}
+        (Some(last), _) => {
+            let next = last.map_or(0, |i| i + 1) % tracks.len();
+            tracks.rotate_left(next);
+        }
+        (None, Selection::Random) => tracks.shuffle(rng),
+        (None, Selection::Recent) => {
+            // a weighted shuffle where the nth track has weight n: each track gets a key of
+            // u^(1/weight) for a random u, and the highest keys go first
+            let mut keyed = tracks
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| (rng.gen::<f64>().powf(1.0 / (i + 1) as f64), t))
+                .collect::<Vec<_>>();
+            keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+            tracks = keyed.into_iter().map(|(_, t)| t).collect();
+        }
+    }
+    tracks
+}
+
+/// Move the recently played tracks (by base62 id) to the end, so they're only picked again
+/// when nothing else in the playlist will do
+fn avoid_recent(tracks: Vec<SpotifyId>, recent: &[String]) -> Vec<SpotifyId> {
+    let (again, fresh): (Vec<SpotifyId>, Vec<SpotifyId>) = tracks
+        .into_iter()
+        .partition(|t| t.to_base62().is_ok_and(|id| recent.contains(&id)));
+    fresh.into_iter().chain(again).collect()
+}
+
+/// Pick the first track that is at least `min_secs` long. If none of them are long enough,
+/// fall back to the first track that could be fetched rather than not playing anything.
+async fn pick_track(
+    session: &Session,
+    tracks: &[SpotifyId],
+    min_secs: u32,
+) -> Option<(SpotifyId, Track)> {
+    let mut fallback = None;
+    for id in tracks {
+        let Ok(track) = Track::get(session, *id).await else {
+            continue;
+        };
+        if track.duration as i64 >= min_secs as i64 * 1000 {
+            return Some((*id, track));
+        }
+        if fallback.is_none() {
+            fallback = Some((*id, track));
+        }
+    }
+    fallback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn ids() -> Vec<SpotifyId> {
+        [
+            "5PbMSJZcNA3p2LZv7C56cm",
+            "6UCFZ9ZOFRxK8oak7MdPZu",
+            "2aBMj4vGrpxavecIWQtcc4",
+            "37i9dQZF1DX0UrRvztWcAU",
+        ]
+        .iter()
+        .map(|id| SpotifyId::from_base62(id).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn track_order_is_deterministic_with_a_seed() {
+        let tracks = ids();
+        let first = track_order(
+            &tracks,
+            None,
+            Selection::Random,
+            &mut StdRng::seed_from_u64(7),
+        );
+        assert_eq!(
+            first,
+            track_order(
+                &tracks,
+                None,
+                Selection::Random,
+                &mut StdRng::seed_from_u64(7)
+            )
+        );
+        let mut sorted = first.clone();
+        sorted.sort_by_key(|id| id.to_base62().unwrap());
+        let mut expected = tracks.clone();
+        expected.sort_by_key(|id| id.to_base62().unwrap());
+        assert_eq!(sorted, expected);
+
+        // resuming ignores the rng and continues after the cursor
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(
+            track_order(&tracks, Some(None), Selection::Random, &mut rng),
+            tracks
+        );
+        assert_eq!(
+            track_order(&tracks, Some(Some(1)), Selection::Random, &mut rng)[0],
+            tracks[2]
+        );
+        assert_eq!(
+            track_order(&tracks, Some(Some(3)), Selection::Random, &mut rng)[0],
+            tracks[0]
+        );
+        assert!(track_order(&[], Some(Some(3)), Selection::Random, &mut rng).is_empty());
+    }
+    #[test]
+    fn avoids_recently_played_tracks() {
+        let tracks = ids();
+        let recent = vec![
+            "5PbMSJZcNA3p2LZv7C56cm".to_string(),
+            "2aBMj4vGrpxavecIWQtcc4".to_string(),
+        ];
+        assert_eq!(
+            avoid_recent(tracks.clone(), &recent),
+            vec![tracks[1], tracks[3], tracks[0], tracks[2]]
+        );
+        assert_eq!(avoid_recent(tracks.clone(), &[]), tracks);
+    }
+    #[test]
+    fn recent_favours_later_tracks() {
+        let tracks = ids();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut firsts = vec![0; tracks.len()];
+        for _ in 0..1000 {
+            let order = track_order(&tracks, None, Selection::Recent, &mut rng);
+            assert_eq!(order.len(), tracks.len());
+            firsts[tracks.iter().position(|t| *t == order[0]).unwrap()] += 1;
+        }
+        // weights 1..=4, so the last track should come first about 4 times as often
+        assert!(firsts.windows(2).all(|w| w[0] < w[1]), "{:?}", firsts);
+        assert!(firsts[3] > firsts[0] * 3, "{:?}", firsts);
+    }
+    /// What the first alarm in `alarms` plays with `config`
+    fn request(config: &str, alarms: &str) -> PlayRequest {
+        let config = crate::config::get_config(config).unwrap();
+        let alarms = crate::alarm::get_alarms(alarms, &config.day_names).unwrap();
+        PlayRequest::for_alarm(&config, &alarms[0], &mut StdRng::seed_from_u64(1))
+    }
+    #[test]
+    fn fade_starts_at_the_floor() {
+        let fade = request("volume=80\nfade_in_secs=10\nfade_min_volume=20", "7:00 M a").envelope;
+        assert_eq!(fade.volume_at(Duration::ZERO), 20);
+        assert_eq!(fade.volume_at(Duration::from_secs(5)), 50);
+        assert_eq!(fade.volume_at(Duration::from_secs(10)), 80);
+        assert_eq!(fade.volume_at(Duration::from_secs(60)), 80);
+
+        // no fade plays at the volume straight away
+        let fade = request("volume=80", "7:00 M a").envelope;
+        assert_eq!(fade.volume_at(Duration::ZERO), 80);
+        assert_eq!(fade.len(), Duration::ZERO);
+
+        // groups and alarms set their own volumes, and the rest comes from the config
+        let config = "volume=80\nfade_in_secs=10\nfade_min_volume=20\ngroup.quiet.volume=40";
+        let req = request(config, "7:00 M group=quiet a");
+        assert_eq!(req.volume, 40);
+        assert_eq!(req.envelope, Envelope::new(40, 10, 20, &[]));
+        let req = request(config, "7:00 M group=quiet volume=60 fade_in_secs=0 a");
+        assert_eq!(req.envelope.volume_at(Duration::ZERO), 60);
+        assert_eq!(mixer_volume(100), u16::MAX);
+        assert_eq!(mixer_volume(0), 0);
+    }
+    #[test]
+    fn follows_the_volume_profile() {
+        let envelope = request(
+            "volume=50",
+            "7:00 M volume_profile=10:20,40:80,100:80,130:70 a",
+        )
+        .envelope;
+        let at = |secs| envelope.volume_at(Duration::from_secs(secs));
+        assert_eq!(
+            [
+                at(0),
+                at(10),
+                at(25),
+                at(40),
+                at(90),
+                at(115),
+                at(130),
+                at(600)
+            ],
+            [20, 20, 50, 80, 80, 75, 70, 70]
+        );
+        assert_eq!(envelope.len(), Duration::from_secs(130));
+    }
+    #[test]
+    fn pick_playlist_is_deterministic_with_a_seed() {
+        let playlists = vec![
+            WeightedPlaylist {
+                uri: "spotify:playlist:2aBMj4vGrpxavecIWQtcc4".to_string(),
+                weight: 1.0,
+            },
+            WeightedPlaylist {
+                uri: "spotify:playlist:37i9dQZF1DX0UrRvztWcAU".to_string(),
+                weight: 1.0,
+            },
+        ];
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20)
+                .map(|_| pick_playlist(&playlists, &mut rng))
+                .collect::<Vec<&str>>()
+        };
+        assert_eq!(picks(1), picks(1));
+        assert!(picks(1).contains(&playlists[0].uri.as_str()));
+        assert!(picks(1).contains(&playlists[1].uri.as_str()));
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let only = [
+            WeightedPlaylist {
+                weight: 0.0,
+                ..playlists[0].clone()
+            },
+            playlists[1].clone(),
+        ];
+        assert!((0..20).all(|_| pick_playlist(&only, &mut rng) == only[1].uri));
+    }
+
+    #[tokio::test]
+    async fn selftest_plays_a_paused_track() {
+        let (events, mut player_event) = tokio::sync::mpsc::unbounded_channel();
+        let playing = PlayerEvent::Playing {
+            play_request_id: 0,
+            track_id: ids()[0],
+            position_ms: 0,
+            duration_ms: 1000,
+        };
+        // a paused track only starts when it's told to
+        let unpause = || events.send(playing).unwrap();
+        let timeout = Duration::from_millis(50);
+        assert!(
+            wait_until_playing(&mut player_event, Some(unpause), timeout)
+                .await
+                .is_ok()
+        );
+        assert!(wait_until_playing(&mut player_event, None::<fn()>, timeout)
+            .await
+            .is_err());
+    }
 }
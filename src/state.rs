@@ -0,0 +1,194 @@
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::util::get_home_path;
+
+const CURSORS_FILE_NAME: &str = "cursors.txt";
+const PLAYED_FILE_NAME: &str = "played.txt";
+const RECENT_FILE_NAME: &str = "recent.txt";
+
+/// Index of the last track played from `playlist` by a resuming alarm
+pub fn get_cursor(playlist: &str) -> Option<usize> {
+    read_cursors().get(playlist).copied()
+}
+
+pub fn set_cursor(playlist: &str, index: usize) -> Result<(), String> {
+    let mut cursors = read_cursors();
+    cursors.insert(playlist.to_string(), index);
+
+    let mut path = get_home_path().map_err(|_| "Unable to get your home directory")?;
+    path.push(CURSORS_FILE_NAME);
+    fs::write(&path, format_cursors(&cursors))
+        .map_err(|e| format!("Unable to write {}: {}", path.to_str().unwrap(), e))
+}
+
+/// The tracks played most recently from `playlist`, oldest first
+pub fn get_recent_tracks(playlist: &str) -> Vec<String> {
+    read_recent().remove(playlist).unwrap_or_default()
+}
+
+/// Record that `track` was played from `playlist`, keeping only the last `keep`
+pub fn add_recent_track(playlist: &str, track: &str, keep: usize) -> Result<(), String> {
+    let mut recent = read_recent();
+    let tracks = recent.entry(playlist.to_string()).or_default();
+    tracks.retain(|t| t != track);
+    tracks.push(track.to_string());
+    let extra = tracks.len().saturating_sub(keep);
+    tracks.drain(..extra);
+
+    let mut path = get_home_path().map_err(|_| "Unable to get your home directory")?;
+    path.push(RECENT_FILE_NAME);
+    fs::write(&path, format_recent(&recent))
+        .map_err(|e| format!("Unable to write {}: {}", path.to_str().unwrap(), e))
+}
+
+/// The alarms that have gone off on `date`, so a restart doesn't play them again
+pub fn get_played(date: NaiveDate) -> Vec<String> {
+    let Ok(mut path) = get_home_path() else {
+        return vec![];
+    };
+    path.push(PLAYED_FILE_NAME);
+    fs::read_to_string(path)
+        .map(|f| parse_played(&f, date))
+        .unwrap_or_default()
+}
+
+/// Record that `alarm` went off on `date`, forgetting anything from earlier days
+pub fn set_played(alarm: &str, date: NaiveDate) -> Result<(), String> {
+    let mut played = get_played(date);
+    played.push(alarm.to_string());
+    write_played(&played, date)
+}
+
+/// Forget that `alarm` went off on `date`, or every alarm if it's None, so it can go off
+/// again. Returns how many were forgotten.
+pub fn clear_played(alarm: Option<&str>, date: NaiveDate) -> Result<usize, String> {
+    let (cleared, kept): (Vec<String>, Vec<String>) = get_played(date)
+        .into_iter()
+        .partition(|a| alarm.is_none_or(|alarm| a == alarm));
+    write_played(&kept, date)?;
+    Ok(cleared.len())
+}
+
+fn write_played(played: &[String], date: NaiveDate) -> Result<(), String> {
+    let mut path = get_home_path().map_err(|_| "Unable to get your home directory")?;
+    path.push(PLAYED_FILE_NAME);
+    fs::write(&path, format_played(played, date))
+        .map_err(|e| format!("Unable to write {}: {}", path.to_str().unwrap(), e))
+}
+
+fn read_cursors() -> HashMap<String, usize> {
+    let Ok(mut path) = get_home_path() else {
+        return HashMap::new();
+    };
+    path.push(CURSORS_FILE_NAME);
+    fs::read_to_string(path)
+        .map(|f| parse_cursors(&f))
+        .unwrap_or_default()
+}
+
+/// Each line is `playlist-uri index`, anything unparseable is ignored
+fn parse_cursors(f: &str) -> HashMap<String, usize> {
+    f.lines()
+        .filter_map(|line| {
+            let (uri, index) = line.split_once(' ')?;
+            Some((uri.to_string(), index.parse::<usize>().ok()?))
+        })
+        .collect()
+}
+
+fn format_cursors(cursors: &HashMap<String, usize>) -> String {
+    let mut lines = cursors
+        .iter()
+        .map(|(uri, index)| format!("{} {}\n", uri, index))
+        .collect::<Vec<String>>();
+    lines.sort();
+    lines.concat()
+}
+
+fn read_recent() -> HashMap<String, Vec<String>> {
+    let Ok(mut path) = get_home_path() else {
+        return HashMap::new();
+    };
+    path.push(RECENT_FILE_NAME);
+    fs::read_to_string(path)
+        .map(|f| parse_recent(&f))
+        .unwrap_or_default()
+}
+
+/// Each line is `playlist-uri track-id`, oldest first
+fn parse_recent(f: &str) -> HashMap<String, Vec<String>> {
+    let mut recent: HashMap<String, Vec<String>> = HashMap::new();
+    for (uri, track) in f.lines().filter_map(|line| line.split_once(' ')) {
+        recent
+            .entry(uri.to_string())
+            .or_default()
+            .push(track.to_string());
+    }
+    recent
+}
+
+fn format_recent(recent: &HashMap<String, Vec<String>>) -> String {
+    let mut uris = recent.keys().collect::<Vec<&String>>();
+    uris.sort();
+    uris.iter()
+        .flat_map(|uri| recent[*uri].iter().map(move |t| format!("{} {}\n", uri, t)))
+        .collect()
+}
+
+/// Each line is `date alarm`, only the lines for `date` are kept
+fn parse_played(f: &str, date: NaiveDate) -> Vec<String> {
+    f.lines()
+        .filter_map(|line| {
+            let (d, alarm) = line.split_once(' ')?;
+            (d.parse::<NaiveDate>().ok()? == date).then(|| alarm.to_string())
+        })
+        .collect()
+}
+
+fn format_played(played: &[String], date: NaiveDate) -> String {
+    played
+        .iter()
+        .map(|alarm| format!("{} {}\n", date, alarm))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let mut cursors = HashMap::new();
+        cursors.insert("spotify:playlist:2aBMj4vGrpxavecIWQtcc4".to_string(), 12);
+        cursors.insert("spotify:playlist:37i9dQZF1DX0UrRvztWcAU".to_string(), 0);
+        assert_eq!(parse_cursors(&format_cursors(&cursors)), cursors);
+        assert!(parse_cursors("spotify:playlist:2aBMj4vGrpxavecIWQtcc4 x\n").is_empty());
+    }
+    #[test]
+    fn recent_tracks_round_trip() {
+        let mut recent = HashMap::new();
+        recent.insert(
+            "spotify:playlist:2aBMj4vGrpxavecIWQtcc4".to_string(),
+            vec![
+                "6UCFZ9ZOFRxK8oak7MdPZu".to_string(),
+                "5PbMSJZcNA3p2LZv7C56cm".to_string(),
+            ],
+        );
+        recent.insert(
+            "spotify:playlist:37i9dQZF1DX0UrRvztWcAU".to_string(),
+            vec!["5PbMSJZcNA3p2LZv7C56cm".to_string()],
+        );
+        assert_eq!(parse_recent(&format_recent(&recent)), recent);
+    }
+    #[test]
+    fn played_is_only_kept_for_the_day() {
+        let today = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        let played = vec!["06:30 wake up".to_string(), "07:00 work".to_string()];
+        let f = format_played(&played, today);
+        assert_eq!(parse_played(&f, today), played);
+        assert!(parse_played(&f, today.succ_opt().unwrap()).is_empty());
+        assert!(parse_played("yesterday 06:30 wake up\n", today).is_empty());
+    }
+}
@@ -12,3 +12,78 @@ pub fn get_home_path() -> Result<path::PathBuf, ()> {
     home_path.push(CACHE_PATH);
     Ok(home_path)
 }
+
+/// Prints librespot's warnings, e.g. when a playlist comes back with fewer tracks than it
+/// should have. Without a logger they're dropped
+struct WarnLogger;
+
+impl log::Log for WarnLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} ({}): {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init_logging() {
+    static LOGGER: WarnLogger = WarnLogger;
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+}
+
+/// Options that take a value, e.g. `--config PATH`
+const VALUE_OPTIONS: &[&str] = &["--config"];
+/// Subcommands, which take the place of the login arguments
+pub const COMMANDS: &[&str] = &["fire", "explain", "reset", "next"];
+
+/// The command line arguments (including the program name) without any `--options`
+pub fn positional_args() -> Vec<String> {
+    let mut args = vec![];
+    let mut iter = std::env::args();
+    while let Some(arg) = iter.next() {
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            iter.next();
+        } else if !arg.starts_with("--") {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+/// The value given to an option, e.g. `--config PATH`
+pub fn option_arg(name: &str) -> Option<String> {
+    let mut iter = std::env::args().skip_while(|a| a != name);
+    iter.next()?;
+    iter.next()
+}
+
+pub fn has_flag(name: &str) -> bool {
+    std::env::args().any(|a| a == name)
+}
+
+/// The subcommand and its arguments, if one was given
+pub fn command() -> Option<(String, Vec<String>)> {
+    let mut args = positional_args().into_iter().skip(1);
+    let command = args.next().filter(|c| COMMANDS.contains(&c.as_str()))?;
+    Some((command, args.collect()))
+}
+
+/// `USERNAME PASSWORD` from the command line, for logging in the first time
+pub fn login_args() -> Option<(String, String)> {
+    match positional_args().as_slice() {
+        [_, user, pass] if command().is_none() => Some((user.clone(), pass.clone())),
+        _ => None,
+    }
+}